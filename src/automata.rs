@@ -1,16 +1,22 @@
-extern crate opencl3 as cl;
 extern crate json;
-use automata::cl::memory::ClMem;
 
-use window;
+use backend::Backend;
+use backend::BackendKind;
+use backend::Blocks;
+use cpu;
+use rule::Rule;
 
 
 /*
 The "field" is a chunk of data where all cells of the game reside.
+For Generations rules (see rule.rs), it also has one byte per cell for the
+state; the bits in "data" then tell which cells are in state 1 (alive).
 */
 
+#[derive(Clone)]
 pub struct Field {
     data: Vec<u8>,
+    states: Vec<u8>,
     pub w: usize,
     pub h: usize,
     pub w8: usize,
@@ -24,6 +30,7 @@ impl Field
         data.resize(h*w8, 0);
         Field {
             data: data,
+            states: Vec::new(),
             w: w,
             h: h,
             w8: w8,
@@ -43,6 +50,74 @@ impl Field
         d &= !(1 << (x%8));
         d |= v8;
         self.data[di] = d;
+        if !self.states.is_empty() {
+            self.states[y*self.w + x] = v as u8;
+        }
+    }
+
+    // Adds the states for Generations rules; the live cells are in state 1.
+    pub fn enable_states (&mut self) {
+        if self.states.is_empty() {
+            self.states = vec![0; self.w*self.h];
+            for y in 0..self.h {
+                for x in 0..self.w {
+                    self.states[y*self.w + x] = self.get(x, y) as u8;
+                }
+            }
+        }
+    }
+
+    pub fn has_states (&self) -> bool {
+        !self.states.is_empty()
+    }
+
+    pub fn state (&self, x:usize, y:usize) -> u8 {
+        if self.states.is_empty() {
+            self.get(x, y) as u8
+        } else {
+            self.states[y*self.w + x]
+        }
+    }
+
+    pub fn set_state (&mut self, x:usize, y:usize, state:u8) {
+        self.enable_states();
+        self.set(x, y, state == 1);
+        self.states[y*self.w + x] = state;
+    }
+
+    // the packed rows, w8 bytes each, as they are transferred to and from a backend
+    pub fn data (&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut (&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    // the states, w bytes per row; empty unless enable_states was called
+    pub fn states (&self) -> &[u8] {
+        &self.states
+    }
+
+    // Call update_data afterwards, so that the bits match the states.
+    pub fn states_mut (&mut self) -> &mut [u8] {
+        &mut self.states
+    }
+
+    // the live cells; bits past w are always 0
+    pub fn population (&self) -> u64 {
+        self.data.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    pub fn update_data (&mut self) {
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let v = self.states[y*self.w + x] == 1;
+                let di = y*self.w8 + x/8;
+                self.data[di] &= !(1 << (x%8));
+                self.data[di] |= (v as u8) << (x%8);
+            }
+        }
     }
 }
 
@@ -54,10 +129,11 @@ It also stores a whole result byte for the case in which an 8×1 field slice
 is filled and surrounded with zeroes, or, respectively, ones.
 */
 
+#[derive(Clone)]
 pub struct Table {
-    values: [u8; 4096/4],
-    zeroes_b: u8,
-    ones_b: u8,
+    pub(crate) values: [u8; 4096/4],
+    pub(crate) zeroes_b: u8,
+    pub(crate) ones_b: u8,
 }
 
 impl Table
@@ -123,13 +199,51 @@ impl Table
         new
     }
     /*
-    fn get (&self, env: u16) -> u8 {
+    Like "new", but for any rule, e.g. with Hensel letters or from a MAP string.
+    */
+    pub fn from_rule (rule: &Rule) -> Self
+    {
+        let mut new = Self {
+            values: [0; 4096/4],
+            zeroes_b: 0,
+            ones_b: 0,
+        };
+        // the 3×3 neighbourhood (see rule.rs) of the cell at column x in env
+        let env_3x3 = |env: u16, x: u16| -> u16 {
+            let mut result = 0u16;
+            for row in 0..3 {
+                result |= ((env >> (row*4 + x - 1)) & 7) << (row*3);
+            }
+            result
+        };
+        for env in 0..4096 {
+            let mut value = 0u8;
+            //left result bit
+            if rule.next(env_3x3(env, 1)) {
+                value |= 1 << 0;
+            }
+            //right result bit
+            if rule.next(env_3x3(env, 2)) {
+                value |= 1 << 1;
+            }
+            new.set(env, value);
+        }
+        //same as in "new", for 8×1 slices surrounded by only zeroes or only ones
+        if rule.next(0) {
+            new.zeroes_b = 0xff;
+        }
+        if rule.next(0x1ff) {
+            new.ones_b = 0xff;
+        }
+        new
+    }
+
+    pub fn get (&self, env: u16) -> u8 {
         //get the u8 with the entry and 1 other entry
         let d = self.values[env as usize / 4];
         //shift the entry to the LSB and clear any MSB past 2 bits
         (d >> ((env%4)*2)) & 3
     }
-    */
     fn set (&mut self, env: u16, value: u8) {
         //get the u8 with the entry and 1 other entry
         let mut d = self.values[env as usize / 4];
@@ -143,7 +257,8 @@ impl Table
     /*
     We'll bake the table into the OpenCL source code, for even better performance.
     */
-    fn as_cl_arr (&self) -> String {
+    #[cfg(feature = "opencl")]
+    pub(crate) fn as_cl_arr (&self) -> String {
         // string length of a byte: max. 6: "0xFF, "
         // overhead: 2: "{...}"
         let length = 6*self.values.len() + 2;
@@ -160,24 +275,106 @@ impl Table
     }
 }
 
+/*
+The "topology" tells what lies beyond the edges of the field: dead cells on a
+bounded plane, or the opposite edge on a torus (like Golly's ":T" suffix).
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    Plane,
+    Torus,
+}
+
+impl Topology
+{
+    pub fn from_seed (seed_json: &json::JsonValue) -> Result<Topology, Box<dyn std::error::Error>>
+    {
+        match seed_json["topology"].as_str().unwrap_or("plane") {
+            "plane" => Ok(Topology::Plane),
+            "torus" => Ok(Topology::Torus),
+            other => Err(Box::new(AutomataError::new(&format!(
+                "Unknown topology \"{}\" in seed.json (expected \"plane\" or \"torus\").",
+                other
+            )))),
+        }
+    }
+}
+
+// as in seed.json
+impl std::fmt::Display for Topology {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Topology::Plane => write!(f, "plane"),
+            Topology::Torus => write!(f, "torus"),
+        }
+    }
+}
+
+/*
+The hashes of the latest generations (see Backend::hash), up to "window" of
+them, for telling static and periodic patterns: if the latest generation has
+the hash of the one p generations before, it repeats itself every p rounds
+from there on (p=1: static). Playing is deterministic, so one repetition is
+enough, and with 64 bits, a mistake due to two fields sharing a hash is
+unlikely enough to be ignored.
+*/
+
+struct History {
+    hashes: std::collections::VecDeque<u64>,
+    window: usize,
+    period: Option<usize>,
+}
+
+impl History
+{
+    fn push (&mut self, hash: u64)
+    {
+        self.period = self.hashes.iter().rev()
+            .position(|&h| h == hash)
+            .map(|i| i + 1);
+        if self.hashes.len() == self.window {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back(hash);
+    }
+
+    fn clear (&mut self)
+    {
+        self.hashes.clear();
+        self.period = None;
+    }
+}
+
+/*
+The cells live on the backend while playing; the (host) field is only
+brought up to date when it's read, e.g. for the window or a snapshot. Two
+flags tell which copy is ahead: "dirty" if the field was changed with "set"
+since the last upload, "stale" if the backend played rounds since the last
+download. They are never both set, as "set" downloads first.
+*/
+
 pub struct Automata {
     pub w: usize,
     pub h: usize,
-    pub field: Field,
-    //optimization
-    #[allow(dead_code)] cl_context: cl::context::Context,
-    fields_swapped: bool,
-    clb_field0: cl::memory::Buffer<u8>,
-    clb_field1: cl::memory::Buffer<u8>,
-    #[allow(dead_code)] clb_table: cl::memory::Buffer<u8>,
-    cl_command_queue: cl::command_queue::CommandQueue,
-    clk_play: cl::kernel::Kernel,
+    field: Field,
+    pub rule: Rule,
+    pub topology: Topology,
+    backend: Box<dyn Backend>,
+    dirty: bool,
+    stale: bool,
+    history: Option<History>,
 }
 
 #[derive(Debug, Clone)]
 pub struct AutomataError {
     msg: String,
 }
+impl AutomataError {
+    pub fn new (msg: &str) -> AutomataError {
+        AutomataError { msg: msg.to_string() }
+    }
+}
 impl std::fmt::Display for AutomataError {
     fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.msg)
@@ -188,204 +385,349 @@ impl std::error::Error for AutomataError {}
 impl Automata
 {
     pub fn new (
-            window: &window::Window,
-            gpu_i: usize,
+            backend_kind: &BackendKind,
             seed_json: &json::JsonValue,
+            seed: Field,
     ) -> Result<Automata, Box<dyn std::error::Error>>
     {
         // seed.json
-        let mut borns = 0u16;
-        let mut survives = 0u16;
-        {
-            let mut bs_str = seed_json["rulestring"].as_str().unwrap().split("/");
-            let mut b_str = bs_str.next().unwrap().chars();
-            assert_eq!('B', b_str.next().unwrap());
-            for born_c in b_str {
-                let born_i = born_c.to_digit(9).unwrap();
-                borns |= 1 << born_i;
-            }
-            let mut s_str = bs_str.next().unwrap().chars();
-            assert_eq!('S', s_str.next().unwrap());
-            for survive_c in s_str {
-                let survive_i = survive_c.to_digit(9).unwrap();
-                survives |= 1 << survive_i;
-            }
-        }
-
-        // seed.png
-        let ((w,h),seed) = window.seed_png();
-    
-        // table, (host) field
-        let field = Field::new(w,h);
-        let table = Table::new(borns, survives);
-
-        // integrate OpenCL
-        let cl_context;
-        let cl_command_queue;
-        let clk_play;
-        let clb_field0: cl::memory::Buffer<u8>;
-        let clb_field1: cl::memory::Buffer<u8>;
-        let mut clb_table: cl::memory::Buffer<u8>;
-        {
-            let mut devices = Vec::<cl::types::cl_device_id>::new();
-            for platform in cl::platform::get_platforms().unwrap() {
-                for device in platform.get_devices(cl::device::CL_DEVICE_TYPE_GPU).unwrap() {
-                    devices.push(device);
-                }
-            }
-            if gpu_i >= devices.len() {
-                return Err(Box::new(AutomataError{
-                    msg: "Cannot find a suitable OpenCL device for gpu_i.".to_string()
-                }));
-            }
-            let device = cl::device::Device::new(devices[gpu_i]);
-            cl_context = cl::context::Context::from_device(&device).unwrap();
-            cl_command_queue = cl::command_queue::CommandQueue::create_with_properties(
-                &cl_context,
-                device.id(),
-                0, //properties
-                0 //queue_size
-            ).unwrap();
-            //bake in lookup values
-            let mut program_source = String::from("__constant uchar TABLE[] = ");
-            program_source += &table.as_cl_arr();
-            program_source.push_str(";\n");
-            program_source.push_str(&*format!("#define ZEROES_B 0x{:X}\n", table.zeroes_b));
-            program_source.push_str(&*format!("#define ONES_B 0x{:X}\n", table.ones_b));
-            program_source.push_str("\n\n");
-            program_source.push_str(&include_str!("kernels.cl"));
-            let program = cl::program::Program::create_and_build_from_source(
-                &cl_context,
-                &*program_source,
-                "" //options
-            ).unwrap();
-            clk_play = cl::kernel::Kernel::new(
-                cl::kernel::create_kernel(
-                    program.get(),
-                    &std::ffi::CString::new("play").unwrap()
-                ).unwrap()
-            );
-            clb_field0 = cl::memory::Buffer::create(
-                &cl_context,
-                cl::memory::CL_MEM_READ_WRITE,
-                h * field.w8,
-                std::ptr::null_mut()
-            ).unwrap();
-            clb_field1 = cl::memory::Buffer::create(
-                &cl_context,
-                cl::memory::CL_MEM_READ_WRITE,
-                h * field.w8,
-                std::ptr::null_mut()
-            ).unwrap();
-            clb_table = cl::memory::Buffer::create(
-                &cl_context,
-                cl::memory::CL_MEM_READ_WRITE,
-                table.values.len(),
-                std::ptr::null_mut()
-            ).unwrap();
-            cl_command_queue.enqueue_write_buffer(
-                &mut clb_table,
-                1, //blocking_write
-                0, //offset
-                &table.values,
-                &[] //event_wait_list
-            ).unwrap();
-            clk_play.set_arg(0, &(w as u32)).unwrap();
-            clk_play.set_arg(1, &(h as u32)).unwrap();
-            // 2 (source) set in loop
-            // 3 (target) set in loop
-            clk_play.set_arg_local_buffer(4, 3*field.w8).unwrap();
-        }
-
-        // create new object
-        let mut new = Automata {
-            w: w,
-            h: h,
-            field: field,
-            cl_context: cl_context,
-            fields_swapped: false,
-            clb_field0: clb_field0,
-            clb_field1: clb_field1,
-            clb_table: clb_table,
-            cl_command_queue: cl_command_queue,
-            clk_play: clk_play,
-        };
+        let rulestring = seed_json["rulestring"].as_str()
+            .ok_or_else(|| AutomataError::new("seed.json lacks a \"rulestring\"."))?;
+        let rule = Rule::parse(rulestring)?;
+        let topology = Topology::from_seed(seed_json)?;
+        Automata::with_rule(backend_kind, &rule, topology, seed)
+    }
 
-        // apply seed.png
-        for y in 0..h {
-            let row = &seed[y];
-            for x in 0..w {
-                let v = (row[x/8] >> (x%8)) & 1 != 0;
-                new.set(x, y, v);
-            }
+    // Like "new", but without seed.json, for using simple_cells as a library.
+    pub fn with_rule (
+            backend_kind: &BackendKind,
+            rule: &Rule,
+            topology: Topology,
+            mut seed: Field,
+    ) -> Result<Automata, Box<dyn std::error::Error>>
+    {
+        // backend; the seed becomes the (host) field
+        let (w, h) = (seed.w, seed.h);
+        if w == 0 || h == 0 {
+            return Err(Box::new(AutomataError::new(&format!(
+                "The field ({}×{}) is empty.", w, h
+            ))));
+        }
+        // on a torus, a neighbourhood wider than the field would count cells twice
+        if topology == Topology::Torus && 2*rule.radius() + 1 > w.min(h) {
+            return Err(Box::new(AutomataError::new(&format!(
+                "The field ({}×{}) is too small for a torus with radius {}.",
+                w, h, rule.radius()
+            ))));
+        }
+        let backend = backend_kind.create(rule, topology, w, h)?;
+        if rule.states > 2 {
+            seed.enable_states();
         }
 
         // all set => return
-        Ok(new)
+        Ok(Automata {
+            w,
+            h,
+            field: seed,
+            rule: *rule,
+            topology,
+            backend,
+            // the backend has nothing yet
+            dirty: true,
+            stale: false,
+            history: None,
+        })
     }
 
-    // Plays n rounds of Game Of Life or so.
+    // Plays n rounds of Game Of Life or so, on the backend (see "field").
     pub fn play (&mut self, n_rounds: usize)
     {
-        // prepare OpenCL
-        let cl_command_queue = &self.cl_command_queue;
-        let (mut clb_source, mut clb_target);
-        if self.fields_swapped {
-            clb_source = &mut self.clb_field1;
-            clb_target = &mut self.clb_field0;
+        self.upload();
+        if self.history.is_some() {
+            for _ in 0..n_rounds {
+                self.backend.play(1);
+                self.record_hash();
+            }
         } else {
-            clb_source = &mut self.clb_field0;
-            clb_target = &mut self.clb_field1;
-        }
-        let clk_play = &self.clk_play;
-        cl_command_queue.enqueue_write_buffer(
-            &mut clb_source,
-            1, //blocking_write
-            0, //offset
-            &self.field.data,
-            &[] //event_wait_list
-        ).unwrap();
-
-        // go
+            self.backend.play(n_rounds);
+        }
+        self.stale |= n_rounds > 0;
+    }
+
+    /*
+    Like "play", but calls after_round with the population after every round,
+    which is counted on the backend without downloading the field.
+    */
+    pub fn play_counting<F: FnMut(u64)> (&mut self, n_rounds: usize, mut after_round: F)
+    {
+        self.upload();
         for _ in 0..n_rounds {
-            if self.fields_swapped {
-                clb_source = &mut self.clb_field1;
-                clb_target = &mut self.clb_field0;
-            } else {
-                clb_source = &mut self.clb_field0;
-                clb_target = &mut self.clb_field1;
+            self.backend.play(1);
+            self.record_hash();
+            after_round(self.backend.population());
+        }
+        self.stale |= n_rounds > 0;
+    }
+
+    // The field, downloaded from the backend first if it played since.
+    pub fn field (&mut self) -> &Field
+    {
+        self.sync();
+        &self.field
+    }
+
+    // Downloads the field from the backend if it played since.
+    fn sync (&mut self)
+    {
+        if self.stale {
+            self.backend.download(&mut self.field);
+            self.stale = false;
+        }
+    }
+
+    /*
+    Keeps the hashes of up to "window" generations from now on, so that
+    "period" can tell when the pattern becomes static or periodic. This takes
+    a hash on the backend after every round. window=0 stops it.
+    */
+    pub fn detect_periods (&mut self, window: usize)
+    {
+        self.history = if window > 0 {
+            Some(History {
+                hashes: std::collections::VecDeque::with_capacity(window),
+                window,
+                period: None,
+            })
+        } else {
+            None
+        };
+    }
+
+    /*
+    After "detect_periods": Some(1) if the latest generation is the same as
+    the one before, Some(p) if it's the same as the one p generations before
+    (the shortest such p, up to the window), None otherwise.
+    */
+    pub fn period (&self) -> Option<usize> {
+        self.history.as_ref().and_then(|history| history.period)
+    }
+
+    /*
+    The share of live cells in each of the blocks (see Blocks), as grey levels
+    (0-255), into out, which has at least nx*ny bytes. Like "population", on
+    the backend unless the field is ahead of it.
+    */
+    pub fn density (&mut self, blocks: &Blocks, out: &mut [u8])
+    {
+        if !self.dirty {
+            self.backend.density(blocks, out);
+        } else if self.field.has_states() {
+            cpu::density(self.field.states(), true, self.w, self.h, blocks, 1, out);
+        } else {
+            cpu::density(self.field.data(), false, self.w, self.h, blocks, 1, out);
+        }
+    }
+
+    // Uploads the field to the backend if it was changed since; the history is void then.
+    fn upload (&mut self)
+    {
+        if self.dirty {
+            self.backend.upload(&self.field);
+            self.dirty = false;
+            if let Some(ref mut history) = self.history {
+                history.clear();
             }
-            clk_play.set_arg(2, &clb_source.get()).unwrap();
-            clk_play.set_arg(3, &clb_target.get()).unwrap();
-            // go, using OpenCL
-            cl_command_queue.enqueue_nd_range_kernel(
-                clk_play.get(),
-                1, //work_dim; for: y=0, y=1, y=2, ... y=h-1
-                [0].as_ptr(), //global_work_offsets
-                [self.h].as_ptr(), //global_work_sizes
-                [1].as_ptr(), //local_work_sizes
-                &[] //event_wait_list
-            ).unwrap();
-            // clean up
-            self.cl_command_queue.finish().unwrap();
-            self.fields_swapped = !self.fields_swapped;
-        }
-
-        // read the results from the GPU
-        cl_command_queue.enqueue_read_buffer(
-            &clb_target,
-            1, //blocking_read
-            0, //offset
-            &mut self.field.data,
-            &[] //event_wait_list
-        ).unwrap();
+        }
+        // the first generation of the history
+        if self.history.as_ref().is_some_and(|history| history.hashes.is_empty()) {
+            self.record_hash();
+        }
     }
 
-    pub fn get (&self, x:usize, y:usize) -> bool {
-        self.field.get(x,y)
+    fn record_hash (&mut self)
+    {
+        if let Some(ref mut history) = self.history {
+            history.push(self.backend.hash());
+        }
     }
 
+    /*
+    The number of live cells (in state 1, for Generations rules), counted on
+    the backend, or on the field if it was changed with "set" since the last
+    upload (or not played yet).
+    */
+    pub fn population (&mut self) -> u64 {
+        if self.dirty {
+            self.field.population()
+        } else {
+            self.backend.population()
+        }
+    }
+
+    pub fn get (&mut self, x:usize, y:usize) -> bool {
+        self.field().get(x,y)
+    }
+
+    // The next "play" uploads the field with the change; the history is void.
     pub fn set (&mut self, x:usize, y:usize, v:bool) {
+        self.sync();
         self.field.set(x,y,v);
+        self.dirty = true;
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+    }
+
+    pub fn state (&mut self, x:usize, y:usize) -> u8 {
+        self.field().state(x,y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn life (w: usize, h: usize, cells: &[(usize, usize)]) -> Automata
+    {
+        let mut seed = Field::new(w, h);
+        for &(x, y) in cells {
+            seed.set(x, y, true);
+        }
+        let rule = Rule::parse("B3/S23").unwrap();
+        Automata::with_rule(&BackendKind::Cpu { threads: 2 }, &rule, Topology::Plane, seed).unwrap()
+    }
+
+    const BLINKER: [(usize, usize); 3] = [(4, 5), (5, 5), (6, 5)];
+
+    #[test]
+    fn empty_field () {
+        let life = Rule::parse("B3/S23").unwrap();
+        let cpu = BackendKind::Cpu { threads: 1 };
+        for (w, h) in [(0, 0), (0, 8), (8, 0)] {
+            let err = Automata::with_rule(&cpu, &life, Topology::Plane, Field::new(w, h)).err().unwrap();
+            assert_eq!(err.to_string(), format!("The field ({}×{}) is empty.", w, h));
+        }
+        assert!(Automata::with_rule(&cpu, &life, Topology::Plane, Field::new(1, 1)).is_ok());
+    }
+
+    #[test]
+    fn population () {
+        // an L becomes a block
+        let mut automata = life(9, 10, &[(2, 2), (3, 2), (2, 3)]);
+        assert_eq!(automata.population(), 3);
+        automata.play(1);
+        assert_eq!(automata.population(), 4);
+        automata.set(7, 7, true);
+        assert_eq!(automata.population(), 5);
+        automata.play(2);
+        assert_eq!(automata.population(), 4);
+    }
+
+    #[test]
+    fn hash () {
+        // the same on every number of threads
+        let mut seed = Field::new(37, 23);
+        for i in 0..37*23 {
+            seed.set(i % 37, i / 37, (i * 7) % 11 < 4);
+        }
+        let rule = Rule::parse("B3/S23").unwrap();
+        let hashes: Vec<u64> = [1, 2, 5].iter().map(|&threads| {
+            let mut backend = BackendKind::Cpu { threads }.create(&rule, Topology::Plane, 37, 23).unwrap();
+            backend.upload(&seed);
+            backend.hash()
+        }).collect();
+        assert_eq!(hashes, vec![hashes[0]; 3]);
+        let mut backend = BackendKind::Cpu { threads: 1 }.create(&rule, Topology::Plane, 37, 23).unwrap();
+        seed.set(36, 22, !seed.get(36, 22));
+        backend.upload(&seed);
+        assert_ne!(backend.hash(), hashes[0]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn periods () {
+        let mut blinker = life(11, 11, &BLINKER);
+        blinker.detect_periods(10);
+        blinker.play(1);
+        assert_eq!(blinker.period(), None);
+        blinker.play(1);
+        assert_eq!(blinker.period(), Some(2));
+        blinker.play(3);
+        assert_eq!(blinker.period(), Some(2));
+        let mut block = life(8, 8, &[(3, 3), (4, 3), (3, 4), (4, 4)]);
+        block.detect_periods(10);
+        block.play(1);
+        assert_eq!(block.period(), Some(1));
+        // a period longer than the window isn't seen
+        let mut blinker = life(11, 11, &BLINKER);
+        blinker.detect_periods(1);
+        blinker.play(4);
+        assert_eq!(blinker.period(), None);
+    }
+
+    // an edit starts the history over
+    #[test]
+    fn periods_after_set () {
+        let mut blinker = life(11, 11, &BLINKER);
+        blinker.detect_periods(10);
+        blinker.play(2);
+        assert_eq!(blinker.period(), Some(2));
+        // a lone cell, which dies at once
+        blinker.set(0, 0, true);
+        assert_eq!(blinker.period(), None);
+        blinker.play(1);
+        assert_eq!(blinker.period(), None);
+        blinker.play(1);
+        assert_eq!(blinker.period(), None);
+        blinker.play(1);
+        assert_eq!(blinker.period(), Some(2));
+    }
+
+    // set, then play: the change is uploaded, and get downloads the result
+    #[test]
+    fn set_play_get () {
+        let mut automata = life(11, 11, &[]);
+        for &(x, y) in &BLINKER {
+            automata.set(x, y, true);
+        }
+        assert!(automata.get(4, 5) && !automata.get(5, 4));
+        automata.play(1);
+        assert!(!automata.get(4, 5) && automata.get(5, 4) && automata.get(5, 6));
+        // an edit after playing keeps what was played
+        automata.set(0, 0, true);
+        assert!(automata.get(5, 4) && automata.get(0, 0));
+        automata.play(1);
+        assert!(automata.get(4, 5) && !automata.get(5, 4) && !automata.get(0, 0));
+    }
+
+    // density on the field while it's ahead of the backend, and on the backend after playing
+    #[test]
+    fn density () {
+        let (w, h) = (11, 11);
+        let blocks = Blocks { x0: 0.0, y0: 0.0, block_w: 1.0, block_h: 1.0, shear: 0.0, nx: w, ny: h };
+        let mut out = vec![0u8; w*h];
+        let cells = |out: &[u8]| -> Vec<(usize, usize)> {
+            (0..w*h).filter(|&i| out[i] == 255).map(|i| (i % w, i / w)).collect()
+        };
+        let mut automata = life(w, h, &BLINKER);
+        automata.density(&blocks, &mut out);
+        assert_eq!(cells(&out), BLINKER.to_vec());
+        automata.play(1);
+        automata.density(&blocks, &mut out);
+        assert_eq!(cells(&out), vec![(5, 4), (5, 5), (5, 6)]);
+        assert_eq!(automata.population(), 3);
+        // dirty: not on the backend yet
+        automata.set(0, 0, true);
+        automata.density(&blocks, &mut out);
+        assert_eq!(cells(&out), vec![(0, 0), (5, 4), (5, 5), (5, 6)]);
+        assert_eq!(automata.population(), 4);
+        automata.play(1);
+        automata.density(&blocks, &mut out);
+        assert_eq!(cells(&out), BLINKER.to_vec());
+        assert_eq!(automata.population(), 3);
+        // after a download, still on the backend
+        assert!(automata.get(4, 5));
+        automata.density(&blocks, &mut out);
+        assert_eq!(cells(&out), BLINKER.to_vec());
+    }
+}
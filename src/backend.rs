@@ -0,0 +1,130 @@
+use automata::AutomataError;
+use automata::Field;
+use automata::Topology;
+use cpu;
+#[cfg(feature = "opencl")]
+use opencl;
+use rule::Rule;
+
+
+/*
+A "backend" is whatever actually plays the rounds on a copy of the field.
+Automata keeps the host field; the backend gets it uploaded before playing,
+and the results are downloaded back into it afterwards. For Generations
+rules, it's the states that are uploaded and downloaded.
+"population" counts the live cells of the backend's copy, without the need to
+download it, and "hash" hashes it (the same way on every backend), e.g. for
+telling whether a generation repeats an earlier one. "density" tells the share
+of live cells in each of the blocks, e.g. for showing a big field in a small
+window.
+*/
+
+pub trait Backend {
+    fn upload (&mut self, field: &Field);
+    fn play (&mut self, n_rounds: usize);
+    fn download (&mut self, field: &mut Field);
+    fn population (&mut self) -> u64;
+    fn hash (&mut self) -> u64;
+    fn density (&mut self, blocks: &Blocks, out: &mut [u8]);
+}
+
+/*
+A grid of nx×ny blocks over the field, block_w×block_h cells each, from
+(x0, y0) on. Each row y is shifted by y*shear cells (0.5 for the sheared view
+of hexagonal rules). The blocks needn't be aligned to the cells: a block
+covers the cells from floor(x0 + bx*block_w) up to floor(x0 + (bx+1)*block_w)
+(likewise for y), or the cell at its centre if it's smaller than a cell.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blocks {
+    pub x0: f64,
+    pub y0: f64,
+    pub block_w: f64,
+    pub block_h: f64,
+    pub shear: f64,
+    pub nx: usize,
+    pub ny: usize,
+}
+
+/*
+Which backend to create, as chosen by the "backend" entry in prefs.json
+(by default "opencl", or "cpu" if built without the "opencl" feature).
+*/
+
+#[derive(Debug, Clone)]
+pub enum BackendKind {
+    OpenCl { gpu_i: usize, kernels: ClKernels },
+    // threads == 0 => as many as the system reports
+    Cpu { threads: usize },
+}
+
+/*
+Which OpenCL kernels play 2-state rules up to radius 1. "tile" is the work
+group size (in bytes × rows) of the tiled kernels ("tile_w" and "tile_h",
+default 16×16), or None for the kernel with a work item per row ("tile_w" or
+"tile_h" 0). With a tile, "block_rounds" (default 4) is the number of rounds
+played per kernel launch. Outer totalistic rules like B3/S23 are played by
+the bit-sliced kernel instead, unless "sliced" is false.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClKernels {
+    pub tile: Option<(usize, usize)>,
+    pub block_rounds: usize,
+    pub sliced: bool,
+}
+
+impl BackendKind
+{
+    pub fn from_prefs (prefs_json: &json::JsonValue) -> Result<BackendKind, Box<dyn std::error::Error>>
+    {
+        let default = if cfg!(feature = "opencl") { "opencl" } else { "cpu" };
+        match prefs_json["backend"].as_str().unwrap_or(default) {
+            "opencl" => Ok(BackendKind::OpenCl {
+                gpu_i: prefs_json["gpu_i"].as_usize().unwrap_or(0),
+                kernels: ClKernels {
+                    tile: match (
+                        prefs_json["tile_w"].as_usize().unwrap_or(16),
+                        prefs_json["tile_h"].as_usize().unwrap_or(16),
+                    ) {
+                        (0, _) | (_, 0) => None,
+                        tile => Some(tile),
+                    },
+                    block_rounds: prefs_json["block_rounds"].as_usize().unwrap_or(4).max(1),
+                    sliced: prefs_json["sliced"].as_bool().unwrap_or(true),
+                },
+            }),
+            "cpu" => Ok(BackendKind::Cpu {
+                threads: prefs_json["cpu_threads"].as_usize().unwrap_or(0),
+            }),
+            other => Err(Box::new(AutomataError::new(&format!(
+                "Unknown backend \"{}\" in prefs.json (expected \"opencl\" or \"cpu\").",
+                other
+            )))),
+        }
+    }
+
+    pub fn create (
+            &self,
+            rule: &Rule,
+            topology: Topology,
+            w: usize,
+            h: usize,
+    ) -> Result<Box<dyn Backend>, Box<dyn std::error::Error>>
+    {
+        match *self {
+            #[cfg(feature = "opencl")]
+            BackendKind::OpenCl { gpu_i, kernels } => Ok(Box::new(
+                opencl::ClBackend::new(gpu_i, kernels, rule, topology, w, h)?
+            )),
+            #[cfg(not(feature = "opencl"))]
+            BackendKind::OpenCl { .. } => Err(Box::new(AutomataError::new(
+                "simple_cells was built without the \"opencl\" feature; please use \"backend\": \"cpu\"."
+            ))),
+            BackendKind::Cpu { threads } => Ok(Box::new(
+                cpu::CpuBackend::new(threads, rule, topology, w, h)
+            )),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use automata::Field;
+use automata::Topology;
+use rule::Rule;
+use rule::RuleError;
+
+
+/*
+A "checkpoint" keeps everything needed to resume a run exactly: the packed
+field data (and the states of Generations rules), the rule, the topology and
+the generation count n. Unlike PNG snapshots, it needs no conversion, which
+also makes it cheap for huge fields. The layout, little-endian:
+    "SCCK"            magic
+    u16               version (1)
+    u16               flags: bit 0: compressed payload, bit 1: states
+    u64, u64, u64     w, h, n
+    u8                topology: 0 plane, 1 torus
+    u32, bytes        length and UTF-8 of the rulestring (see Rule's Display)
+    u64, bytes        length and payload: h*w8 data bytes, then w*h states
+    u32               CRC-32 of all bytes before it
+The compression is a byte-wise run length encoding (like PackBits), which
+suits fields with large empty or filled areas: a control byte c is followed
+by c+1 literal bytes (c < 128), or by 1 byte that repeats c-126 times.
+*/
+
+pub struct Checkpoint {
+    pub field: Field,
+    pub rule: Rule,
+    pub topology: Topology,
+    pub n: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointError {
+    // not a checkpoint file
+    BadMagic,
+    UnsupportedVersion(u16),
+    // the file ends too early
+    Truncated,
+    ChecksumMismatch,
+    InvalidRule(RuleError),
+    InvalidTopology(u8),
+    // the payload doesn't match w and h
+    InvalidPayload,
+}
+
+impl std::fmt::Display for CheckpointError {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            CheckpointError::BadMagic =>
+                write!(f, "This is not a checkpoint file."),
+            CheckpointError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported checkpoint version {} (expected {}).", version, VERSION),
+            CheckpointError::Truncated =>
+                write!(f, "The checkpoint file is truncated."),
+            CheckpointError::ChecksumMismatch =>
+                write!(f, "The checkpoint file is corrupt (checksum mismatch)."),
+            CheckpointError::InvalidRule(ref err) =>
+                write!(f, "Invalid rule in the checkpoint file: {}", err),
+            CheckpointError::InvalidTopology(topology) =>
+                write!(f, "Invalid topology {} in the checkpoint file.", topology),
+            CheckpointError::InvalidPayload =>
+                write!(f, "The field data in the checkpoint file doesn't match its size."),
+        }
+    }
+}
+impl std::error::Error for CheckpointError {}
+
+const MAGIC: &[u8; 4] = b"SCCK";
+const VERSION: u16 = 1;
+const FLAG_COMPRESSED: u16 = 1 << 0;
+const FLAG_STATES: u16 = 1 << 1;
+
+pub fn save (
+        path: &str,
+        field: &Field,
+        rule: &Rule,
+        topology: Topology,
+        n: usize,
+        compress: bool,
+) -> std::io::Result<()>
+{
+    std::fs::write(path, to_bytes(field, rule, topology, n, compress))
+}
+
+pub fn load (path: &str) -> Result<Checkpoint, Box<dyn std::error::Error>>
+{
+    let bytes = std::fs::read(path)?;
+    Ok(from_bytes(&bytes)?)
+}
+
+pub fn to_bytes (field: &Field, rule: &Rule, topology: Topology, n: usize, compress: bool) -> Vec<u8>
+{
+    let mut payload = field.data().to_vec();
+    payload.extend_from_slice(field.states());
+    if compress {
+        payload = pack(&payload);
+    }
+    let mut flags = 0;
+    if compress {
+        flags |= FLAG_COMPRESSED;
+    }
+    if field.has_states() {
+        flags |= FLAG_STATES;
+    }
+    let rulestring = rule.to_string();
+    let mut bytes = Vec::with_capacity(payload.len() + rulestring.len() + 64);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&flags.to_le_bytes());
+    bytes.extend_from_slice(&(field.w as u64).to_le_bytes());
+    bytes.extend_from_slice(&(field.h as u64).to_le_bytes());
+    bytes.extend_from_slice(&(n as u64).to_le_bytes());
+    bytes.push(match topology {
+        Topology::Plane => 0,
+        Topology::Torus => 1,
+    });
+    bytes.extend_from_slice(&(rulestring.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(rulestring.as_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    let checksum = crc32(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+pub fn from_bytes (bytes: &[u8]) -> Result<Checkpoint, CheckpointError>
+{
+    if bytes.len() < 4 || &bytes[..4] != MAGIC {
+        return Err(CheckpointError::BadMagic);
+    }
+    if bytes.len() < 8 {
+        return Err(CheckpointError::Truncated);
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    let mut reader = Reader { bytes: content, pos: 4 };
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(CheckpointError::UnsupportedVersion(version));
+    }
+    if crc32(content).to_le_bytes() != checksum {
+        return Err(CheckpointError::ChecksumMismatch);
+    }
+    let flags = reader.u16()?;
+    let w = reader.u64()? as usize;
+    let h = reader.u64()? as usize;
+    let n = reader.u64()? as usize;
+    let topology = match reader.take(1)?[0] {
+        0 => Topology::Plane,
+        1 => Topology::Torus,
+        topology => return Err(CheckpointError::InvalidTopology(topology)),
+    };
+    let rule_len = reader.u32()? as usize;
+    let rulestring = std::str::from_utf8(reader.take(rule_len)?)
+        .map_err(|_| CheckpointError::InvalidRule(RuleError::Empty))?;
+    let rule = Rule::parse(rulestring).map_err(CheckpointError::InvalidRule)?;
+    let payload_len = reader.u64()? as usize;
+    let payload = reader.take(payload_len)?;
+    let payload = if flags & FLAG_COMPRESSED != 0 {
+        unpack(payload).ok_or(CheckpointError::InvalidPayload)?
+    } else {
+        payload.to_vec()
+    };
+    // check the sizes before allocating anything for them
+    let data_len = h.checked_mul(w.div_ceil(8));
+    let states_len = if flags & FLAG_STATES != 0 { w.checked_mul(h) } else { Some(0) };
+    let (data_len, states_len) = match (data_len, states_len) {
+        (Some(data_len), Some(states_len)) if data_len.checked_add(states_len) == Some(payload.len()) =>
+            (data_len, states_len),
+        _ => return Err(CheckpointError::InvalidPayload),
+    };
+    let mut field = Field::new(w, h);
+    field.data_mut().copy_from_slice(&payload[..data_len]);
+    if states_len > 0 {
+        field.enable_states();
+        field.states_mut().copy_from_slice(&payload[data_len..]);
+    }
+    Ok(Checkpoint {
+        field,
+        rule,
+        topology,
+        n,
+    })
+}
+
+// reads the fields of a checkpoint one after another
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a>
+{
+    fn take (&mut self, len: usize) -> Result<&'a [u8], CheckpointError>
+    {
+        if len > self.bytes.len() - self.pos {
+            return Err(CheckpointError::Truncated);
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len .. self.pos])
+    }
+
+    fn u16 (&mut self) -> Result<u16, CheckpointError>
+    {
+        let mut le = [0; 2];
+        le.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(le))
+    }
+
+    fn u32 (&mut self) -> Result<u32, CheckpointError>
+    {
+        let mut le = [0; 4];
+        le.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(le))
+    }
+
+    fn u64 (&mut self) -> Result<u64, CheckpointError>
+    {
+        let mut le = [0; 8];
+        le.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(le))
+    }
+}
+
+// the run length encoding described above
+fn pack (bytes: &[u8]) -> Vec<u8>
+{
+    let mut packed = Vec::with_capacity(bytes.len() / 8 + 16);
+    let mut i = 0;
+    while i < bytes.len() {
+        let mut run = 1;
+        while run < 129 && i + run < bytes.len() && bytes[i + run] == bytes[i] {
+            run += 1;
+        }
+        if run >= 2 {
+            packed.push((run + 126) as u8);
+            packed.push(bytes[i]);
+            i += run;
+            continue;
+        }
+        // literals, up to the next run of 2 or more
+        let start = i;
+        while i < bytes.len() && i - start < 128 {
+            if i + 1 < bytes.len() && bytes[i + 1] == bytes[i] {
+                break;
+            }
+            i += 1;
+        }
+        packed.push((i - start - 1) as u8);
+        packed.extend_from_slice(&bytes[start..i]);
+    }
+    packed
+}
+
+fn unpack (packed: &[u8]) -> Option<Vec<u8>>
+{
+    let mut bytes = Vec::with_capacity(packed.len() * 4);
+    let mut i = 0;
+    while i < packed.len() {
+        let c = packed[i] as usize;
+        i += 1;
+        if c < 128 {
+            bytes.extend_from_slice(packed.get(i .. i + c + 1)?);
+            i += c + 1;
+        } else {
+            let b = *packed.get(i)?;
+            bytes.resize(bytes.len() + c - 126, b);
+            i += 1;
+        }
+    }
+    Some(bytes)
+}
+
+// CRC-32 (IEEE), as in PNG and zip
+fn crc32 (bytes: &[u8]) -> u32
+{
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trip () {
+        let mut long: Vec<u8> = (0..1000).map(|i| (i*i % 251) as u8).collect();
+        long.extend([7; 1000]);
+        long.extend([1, 2, 2, 3, 3, 3, 4]);
+        for bytes in [vec![], vec![5], vec![5, 5], vec![0; 129], vec![0; 130], (0..=255).collect(), long] {
+            assert_eq!(unpack(&pack(&bytes)), Some(bytes.clone()));
+        }
+        // runs become 2 bytes
+        assert_eq!(pack(&[9; 129]), vec![255, 9]);
+        // a control byte without its bytes
+        assert_eq!(unpack(&[3, 1, 2]), None);
+        assert_eq!(unpack(&[200]), None);
+    }
+
+    #[test]
+    fn crc () {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn round_trip () {
+        let mut field = Field::new(37, 11);
+        field.enable_states();
+        for i in 0..37*11 {
+            if i % 3 != 0 {
+                field.set_state(i % 37, i / 37, (i % 5) as u8);
+            }
+        }
+        // a MAP rule with a "/" in it, and states
+        let rule = Rule::parse("MAPARYXfhZofugWaH7oaIDogBZofuhogOiAaIDogIAAgAAWaH7oaIDogGiA6ICAAIAAaIDogIAAgACAAIAAAAAAAA/C5").unwrap();
+        for compress in [false, true] {
+            let bytes = to_bytes(&field, &rule, Topology::Torus, 12345, compress);
+            let checkpoint = from_bytes(&bytes).unwrap();
+            assert_eq!((checkpoint.field.w, checkpoint.field.h), (37, 11));
+            assert!(checkpoint.field.data() == field.data());
+            assert!(checkpoint.field.states() == field.states());
+            assert_eq!(checkpoint.rule, rule);
+            assert_eq!(checkpoint.topology, Topology::Torus);
+            assert_eq!(checkpoint.n, 12345);
+        }
+    }
+
+    #[test]
+    fn corrupt () {
+        let field = Field::new(64, 64);
+        let bytes = to_bytes(&field, &Rule::parse("B3/S23").unwrap(), Topology::Plane, 1, true);
+        assert!(from_bytes(&bytes).is_ok());
+        // any flipped bit is caught by the CRC
+        for i in 8..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert_eq!(from_bytes(&corrupt).err(), Some(CheckpointError::ChecksumMismatch));
+        }
+        assert_eq!(from_bytes(b"SCCX").err(), Some(CheckpointError::BadMagic));
+        assert_eq!(from_bytes(&bytes[..6]).err(), Some(CheckpointError::Truncated));
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(from_bytes(&version).err(), Some(CheckpointError::UnsupportedVersion(2)));
+    }
+}
@@ -0,0 +1,645 @@
+use automata::Field;
+use automata::Table;
+use automata::Topology;
+use backend::Backend;
+use backend::Blocks;
+use rule::Rule;
+use rule::Transition;
+
+
+/*
+Plays the rounds on the CPU, with the same table lookups as the "play" kernel
+in kernels.cl. The rows are split into one chunk per thread.
+For Generations rules, source and target have one byte (the state) per cell,
+like for "play_generations". Larger-than-Life rules beyond radius 1 are
+counted like in "play_ltl".
+*/
+
+pub struct CpuBackend {
+    w: usize,
+    h: usize,
+    w8: usize,
+    threads: usize,
+    table: Table,
+    rule: Rule,
+    // for Larger-than-Life rules: see Neighbourhood::extent, for dy=-radius..=radius
+    extents: Vec<usize>,
+    topology: Topology,
+    source: Vec<u8>,
+    target: Vec<u8>,
+}
+
+impl CpuBackend
+{
+    pub fn new (
+            threads: usize,
+            rule: &Rule,
+            topology: Topology,
+            w: usize,
+            h: usize,
+    ) -> CpuBackend
+    {
+        let w8 = ((w as f64) / 8_f64).ceil() as usize;
+        let threads = if threads > 0 {
+            threads
+        } else {
+            std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        };
+        // for Generations rules, one byte per cell
+        let row_size = if rule.states > 2 { w } else { w8 };
+        CpuBackend {
+            w,
+            h,
+            w8,
+            threads,
+            table: Table::from_rule(rule),
+            rule: *rule,
+            extents: match rule.transition {
+                Transition::LargerThanLife { radius, neighbourhood, .. } => (0..=2*radius)
+                    .map(|i| neighbourhood.extent(radius, i.abs_diff(radius)))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            topology,
+            source: vec![0; h*row_size],
+            target: vec![0; h*row_size],
+        }
+    }
+}
+
+impl Backend for CpuBackend
+{
+    fn upload (&mut self, field: &Field)
+    {
+        if self.rule.states > 2 {
+            self.source.copy_from_slice(field.states());
+        } else {
+            self.source.copy_from_slice(field.data());
+        }
+    }
+
+    fn play (&mut self, n_rounds: usize)
+    {
+        let (w, h, w8) = (self.w, self.h, self.w8);
+        let generations = self.rule.states > 2;
+        let ltl = self.rule.radius() > 1;
+        let row_size = if generations { w } else { w8 };
+        // at least 1, for empty fields (as in "population")
+        let rows_per_thread = h.div_ceil(self.threads).max(1);
+        for _ in 0..n_rounds {
+            let table = &self.table;
+            let rule = &self.rule;
+            let extents = &self.extents;
+            let topology = self.topology;
+            let source = &self.source;
+            let target = &mut self.target;
+            std::thread::scope(|scope| {
+                let chunks = target.chunks_mut((rows_per_thread * row_size).max(1));
+                for (chunk_i, chunk) in chunks.enumerate() {
+                    scope.spawn(move || {
+                        let y0 = chunk_i * rows_per_thread;
+                        for (dy, row) in chunk.chunks_mut(row_size).enumerate() {
+                            if generations {
+                                play_row_generations(rule, topology, w, h, source, y0 + dy, row);
+                            } else if ltl {
+                                play_row_ltl(rule, extents, topology, w, h, w8, source, y0 + dy, row);
+                            } else {
+                                play_row(table, topology, w, h, w8, source, y0 + dy, row);
+                            }
+                        }
+                    });
+                }
+            });
+            std::mem::swap(&mut self.source, &mut self.target);
+        }
+    }
+
+    fn download (&mut self, field: &mut Field)
+    {
+        if self.rule.states > 2 {
+            field.states_mut().copy_from_slice(&self.source);
+            field.update_data();
+        } else {
+            field.data_mut().copy_from_slice(&self.source);
+        }
+    }
+
+    // the CPU equivalent of the "population" kernel
+    fn population (&mut self) -> u64
+    {
+        let generations = self.rule.states > 2;
+        let chunk_size = self.source.len().div_ceil(self.threads).max(1);
+        std::thread::scope(|scope| {
+            let sums: Vec<_> = self.source.chunks(chunk_size).map(|chunk| {
+                scope.spawn(move || {
+                    if generations {
+                        chunk.iter().filter(|&&state| state == 1).count() as u64
+                    } else {
+                        chunk.iter().map(|b| b.count_ones() as u64).sum::<u64>()
+                    }
+                })
+            }).collect();
+            sums.into_iter().map(|sum| sum.join().unwrap()).sum()
+        })
+    }
+
+    // the CPU equivalent of the "hash" kernel, with the same results
+    fn hash (&mut self) -> u64
+    {
+        let chunk_size = self.source.len().div_ceil(self.threads).max(1);
+        std::thread::scope(|scope| {
+            let sums: Vec<_> = self.source.chunks(chunk_size).enumerate().map(|(chunk_i, chunk)| {
+                scope.spawn(move || {
+                    let i0 = chunk_i * chunk_size;
+                    chunk.iter().enumerate()
+                        .filter(|&(_, &b)| b != 0)
+                        .fold(0u64, |sum, (i, &b)| sum.wrapping_add(splitmix64((((i0 + i) as u64) << 8) | b as u64)))
+                })
+            }).collect();
+            sums.into_iter().fold(0u64, |sum, chunk_sum| sum.wrapping_add(chunk_sum.join().unwrap()))
+        })
+    }
+
+    fn density (&mut self, blocks: &Blocks, out: &mut [u8])
+    {
+        density(&self.source, self.rule.states > 2, self.w, self.h, blocks, self.threads, out);
+    }
+}
+
+/*
+The CPU equivalent of the "density" kernel in kernels.cl, for the field bytes
+(or states, for Generations rules) of a w×h field, with the block rows split
+among the threads. out has one byte per block.
+*/
+pub fn density (
+        field: &[u8],
+        generations: bool,
+        w: usize,
+        h: usize,
+        blocks: &Blocks,
+        threads: usize,
+        out: &mut [u8],
+)
+{
+    let w8 = w.div_ceil(8);
+    if blocks.nx == 0 || blocks.ny == 0 {
+        return;
+    }
+    let rows_per_thread = blocks.ny.div_ceil(threads.max(1));
+    let out = &mut out[..blocks.nx * blocks.ny];
+    std::thread::scope(|scope| {
+        for (chunk_i, chunk) in out.chunks_mut(rows_per_thread * blocks.nx).enumerate() {
+            scope.spawn(move || {
+                for (i, out) in chunk.iter_mut().enumerate() {
+                    let (bx, by) = (i % blocks.nx, chunk_i * rows_per_thread + i / blocks.nx);
+                    let ys = block_range(blocks.y0 + by as f64 * blocks.block_h, blocks.block_h);
+                    let (mut live, mut area) = (0u64, 0u64);
+                    for y in ys.0..ys.1 {
+                        let xs = block_range(
+                            blocks.x0 + bx as f64 * blocks.block_w + y as f64 * blocks.shear,
+                            blocks.block_w
+                        );
+                        area += (xs.1 - xs.0) as u64;
+                        if y < 0 || y >= h as i64 {
+                            continue;
+                        }
+                        let x_start = xs.0.clamp(0, w as i64);
+                        let x_range = x_start as usize .. xs.1.clamp(x_start, w as i64) as usize;
+                        let y = y as usize;
+                        live += if generations {
+                            field[y*w..(y+1)*w][x_range].iter().filter(|&&state| state == 1).count() as u64
+                        } else {
+                            let row = &field[y*w8..(y+1)*w8];
+                            x_range.map(|x| ((row[x/8] >> (x%8)) & 1) as u64).sum()
+                        };
+                    }
+                    *out = ((255*live + area/2) / area) as u8;
+                }
+            });
+        }
+    });
+}
+
+// as "block_range" in kernels.cl, for the block from a on that is size cells long
+fn block_range (a: f64, size: f64) -> (i64, i64)
+{
+    let b = a + size;
+    let (start, end) = (a.floor() as i64, b.floor() as i64);
+    if end <= start {
+        let start = ((a + b) / 2.0).floor() as i64;
+        (start, start + 1)
+    } else {
+        (start, end)
+    }
+}
+
+/*
+The CPU equivalent of "splitmix64" in kernels.cl.
+*/
+fn splitmix64 (mut x: u64) -> u64
+{
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    x
+}
+
+/*
+The CPU equivalent of "work_byte" in kernels.cl.
+*/
+#[allow(clippy::too_many_arguments)]
+fn work_byte (
+        table: &Table,
+        tl: u16, tm: u16, tr: u16,
+        ml: u16, mm: u16, mr: u16,
+        bl: u16, bm: u16, br: u16,
+) -> u8
+{
+    // Is this environment filled with zeroes or ones?
+    if (
+            ((tl>>7)&1) | tm | (tr&1) |
+            ((ml>>7)&1) | mm | (mr&1) |
+            ((bl>>7)&1) | bm | (br&1)
+        ) == 0x00
+    {
+        return table.zeroes_b;
+    }
+    if (
+            (((tl>>7)&1)|0xfe) & tm & ((tr&1)|0xfe) &
+            (((ml>>7)&1)|0xfe) & mm & ((mr&1)|0xfe) &
+            (((bl>>7)&1)|0xfe) & bm & ((br&1)|0xfe)
+        ) == 0xff
+    {
+        return table.ones_b;
+    }
+    // It's more complicated than that => work on the bits.
+    let mut result = 0u8;
+    let env_0 =
+        (tl>>7) | ((tm&7)<<1) |
+        ((ml>>7)<<4) | ((mm&7)<<5) |
+        ((bl>>7)<<8) | ((bm&7)<<9) ;
+    result |= table.get(env_0);
+    let env_2 = ((tm>>1)&15) | (((mm>>1)&15)<<4) | (((bm>>1)&15)<<8);
+    result |= table.get(env_2) << 2;
+    let env_4 = ((tm>>3)&15) | (((mm>>3)&15)<<4) | (((bm>>3)&15)<<8);
+    result |= table.get(env_4) << 4;
+    let env_6 =
+        (tm>>5) | ((tr&1)<<3) |
+        ((mm>>5)<<4) | ((mr&1)<<7) |
+        ((bm>>5)<<8) | ((br&1)<<11) ;
+    result |= table.get(env_6) << 6;
+    result
+}
+
+/*
+The CPU equivalent of "torus_byte" in kernels.cl.
+*/
+fn torus_byte (row: &[u8], w: usize, x8: isize) -> u16
+{
+    let mut result = 0u16;
+    for bi in 0..8 {
+        let x = (x8*8 + bi).rem_euclid(w as isize) as usize;
+        result |= (((row[x/8] >> (x%8)) & 1) as u16) << bi;
+    }
+    result
+}
+
+/*
+The CPU equivalent of "play" in kernels.cl, for the row specified by y.
+*/
+#[allow(clippy::too_many_arguments)]
+fn play_row (
+        table: &Table,
+        topology: Topology,
+        w: usize,
+        h: usize,
+        w8: usize,
+        source: &[u8],
+        y: usize,
+        target: &mut [u8],
+)
+{
+    // in case there are bits in the byte of the row (y) that need to be cleared
+    let cutoff = if w.is_multiple_of(8) { 0 } else { 0xff_u8 << (w%8) };
+    // source rows ("t","m","b": top, mid and bottom rows)
+    let row = |y: isize| -> Option<&[u8]> {
+        let y = match topology {
+            Topology::Plane if y < 0 || y >= h as isize => return None,
+            Topology::Plane => y as usize,
+            Topology::Torus => y.rem_euclid(h as isize) as usize,
+        };
+        Some(&source[y*w8 .. (y+1)*w8])
+    };
+    let (t, m, b) = (row(y as isize - 1), row(y as isize), row(y as isize + 1));
+    // bytes of a source row; beyond the edges, these are 0 or wrap around
+    let g = |r: Option<&[u8]>, x8: isize| -> u16 {
+        match r {
+            None => 0,
+            Some(r) if x8 >= 0 && x8 < w8 as isize - 1 => r[x8 as usize] as u16,
+            Some(r) => match topology {
+                Topology::Plane if x8 == w8 as isize - 1 => r[x8 as usize] as u16,
+                Topology::Plane => 0,
+                Topology::Torus => torus_byte(r, w, x8),
+            },
+        }
+    };
+    for x8 in 0..w8 {
+        let x8 = x8 as isize;
+        let mut v = work_byte(
+            table,
+            g(t, x8-1), g(t, x8), g(t, x8+1),
+            g(m, x8-1), g(m, x8), g(m, x8+1),
+            g(b, x8-1), g(b, x8), g(b, x8+1),
+        );
+        if x8 == w8 as isize - 1 {
+            v &= !cutoff;
+        }
+        target[x8 as usize] = v;
+    }
+}
+
+/*
+The CPU equivalent of "play_generations" in kernels.cl, for the row specified
+by y.
+*/
+fn play_row_generations (
+        rule: &Rule,
+        topology: Topology,
+        w: usize,
+        h: usize,
+        source: &[u8],
+        y: usize,
+        target: &mut [u8],
+)
+{
+    for (x, cell) in target.iter_mut().enumerate() {
+        // the neighbours in state 1
+        let mut env = 0u16;
+        for dy in -1..=1_isize {
+            for dx in -1..=1_isize {
+                let (nx, ny) = (x as isize + dx, y as isize + dy);
+                let (nx, ny) = match topology {
+                    Topology::Plane if nx < 0 || ny < 0
+                        || nx >= w as isize || ny >= h as isize => continue,
+                    Topology::Plane => (nx as usize, ny as usize),
+                    Topology::Torus => (
+                        nx.rem_euclid(w as isize) as usize,
+                        ny.rem_euclid(h as isize) as usize,
+                    ),
+                };
+                if source[ny*w + nx] == 1 {
+                    env |= 1 << ((dy+1)*3 + dx+1);
+                }
+            }
+        }
+        *cell = rule.next_state(source[y*w + x], env);
+    }
+}
+
+/*
+The CPU equivalent of "play_ltl" in kernels.cl, for the row specified by y.
+*/
+#[allow(clippy::too_many_arguments)]
+fn play_row_ltl (
+        rule: &Rule,
+        extents: &[usize],
+        topology: Topology,
+        w: usize,
+        h: usize,
+        w8: usize,
+        source: &[u8],
+        y: usize,
+        target: &mut [u8],
+)
+{
+    let (middle, survives, borns) = match rule.transition {
+        Transition::LargerThanLife { middle, survives, borns, .. } => (middle, survives, borns),
+        _ => unreachable!("play_row_ltl is for Larger-than-Life rules only."),
+    };
+    let radius = extents.len() as isize / 2;
+    // the cell at x of a row; beyond the edges, 0 or the other side
+    let cell = |row: &[u8], x: isize| -> usize {
+        let x = match topology {
+            Topology::Plane if x < 0 || x >= w as isize => return 0,
+            Topology::Plane => x as usize,
+            Topology::Torus => x.rem_euclid(w as isize) as usize,
+        };
+        ((row[x/8] >> (x%8)) & 1) as usize
+    };
+    let mut counts = vec![0usize; w];
+    for dy in -radius..=radius {
+        let ny = y as isize + dy;
+        let ny = match topology {
+            Topology::Plane if ny < 0 || ny >= h as isize => continue,
+            Topology::Plane => ny as usize,
+            Topology::Torus => ny.rem_euclid(h as isize) as usize,
+        };
+        let row = &source[ny*w8 .. (ny+1)*w8];
+        let extent = extents[(dy + radius) as usize] as isize;
+        // slide the count along the row
+        let mut count: usize = (-extent..=extent).map(|nx| cell(row, nx)).sum();
+        counts[0] += count;
+        for (x, x_count) in counts.iter_mut().enumerate().skip(1) {
+            let x = x as isize;
+            count += cell(row, x + extent);
+            count -= cell(row, x - 1 - extent);
+            *x_count += count;
+        }
+    }
+    let row = &source[y*w8 .. (y+1)*w8];
+    target.fill(0);
+    for (x, &count) in counts.iter().enumerate() {
+        let alive = cell(row, x as isize);
+        let count = if middle { count } else { count - alive };
+        let (min, max) = if alive != 0 { survives } else { borns };
+        if (min..=max).contains(&count) {
+            target[x/8] |= 1 << (x%8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rule::Neighbourhood;
+
+    // a pseudo-random field, about a third alive
+    fn random_field (w: usize, h: usize, seed: u64) -> Field
+    {
+        let mut field = Field::new(w, h);
+        let mut x = seed;
+        for y in 0..h {
+            for cx in 0..w {
+                x = splitmix64(x.wrapping_add(1));
+                field.set(cx, y, x.is_multiple_of(3));
+            }
+        }
+        field
+    }
+
+    // the field after one round, cell by cell with Rule::next
+    fn reference (rule: &Rule, topology: Topology, field: &Field) -> Field
+    {
+        let (w, h) = (field.w as isize, field.h as isize);
+        let mut next = Field::new(field.w, field.h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut env = 0u16;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let (nx, ny) = match topology {
+                            Topology::Plane => (x + dx, y + dy),
+                            Topology::Torus => ((x + dx).rem_euclid(w), (y + dy).rem_euclid(h)),
+                        };
+                        if nx >= 0 && ny >= 0 && nx < w && ny < h && field.get(nx as usize, ny as usize) {
+                            env |= 1 << ((dy+1)*3 + dx+1);
+                        }
+                    }
+                }
+                next.set(x as usize, y as usize, rule.next(env));
+            }
+        }
+        next
+    }
+
+    // plays the field on a CpuBackend and checks every round against the reference
+    fn check (rulestring: &str, topology: Topology, field: &Field, threads: usize, rounds: usize)
+    {
+        let rule = Rule::parse(rulestring).unwrap();
+        let mut backend = CpuBackend::new(threads, &rule, topology, field.w, field.h);
+        backend.upload(field);
+        let mut expected = field.clone();
+        let mut played = Field::new(field.w, field.h);
+        for round in 1..=rounds {
+            backend.play(1);
+            backend.download(&mut played);
+            expected = reference(&rule, topology, &expected);
+            assert!(
+                played.data() == expected.data(),
+                "{} on a {}×{} field, {} threads: round {} differs", rulestring, field.w, field.h, threads, round
+            );
+        }
+    }
+
+    #[test]
+    fn rules () {
+        for rulestring in ["B3/S23", "B36/S23", "B2n3/S23-q", "B2ei3-jr/S1c2-kn4w", "B2/S34H", "B2/S013V"] {
+            for (w, h) in [(13, 11), (29, 17), (8, 9)] {
+                let field = random_field(w, h, (w*h) as u64);
+                for threads in [1, 3, 8] {
+                    check(rulestring, Topology::Plane, &field, threads, 8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn torus () {
+        for rulestring in ["B3/S23", "B2n3/S23-q", "B2/S34H"] {
+            for (w, h) in [(13, 11), (21, 5), (16, 7)] {
+                let field = random_field(w, h, (w + h) as u64);
+                for threads in [1, 4] {
+                    check(rulestring, Topology::Torus, &field, threads, 8);
+                }
+            }
+        }
+        // a glider crosses every edge and corner and comes back after 4w rounds
+        let w = 13;
+        let rule = Rule::parse("B3/S23").unwrap();
+        let mut glider = Field::new(w, w);
+        for (x, y) in [(11, 10), (12, 11), (10, 12), (11, 12), (12, 12)] {
+            glider.set(x, y, true);
+        }
+        let mut backend = CpuBackend::new(2, &rule, Topology::Torus, w, w);
+        backend.upload(&glider);
+        let mut played = Field::new(w, w);
+        backend.play(4*w - 1);
+        backend.download(&mut played);
+        assert!(played.data() != glider.data());
+        backend.play(1);
+        backend.download(&mut played);
+        assert!(played.data() == glider.data());
+        // a block with a cell in each corner is still
+        let mut block = Field::new(w, 9);
+        for (x, y) in [(0, 0), (w - 1, 0), (0, 8), (w - 1, 8)] {
+            block.set(x, y, true);
+        }
+        let mut backend = CpuBackend::new(1, &rule, Topology::Torus, w, 9);
+        backend.upload(&block);
+        backend.play(3);
+        let mut played = Field::new(w, 9);
+        backend.download(&mut played);
+        assert!(played.data() == block.data());
+    }
+
+    // one round of a Larger-than-Life rule, counting every cell within the radius
+    fn ltl_reference (rule: &Rule, topology: Topology, field: &Field) -> Field
+    {
+        let (radius, middle, survives, borns, neighbourhood) = match rule.transition {
+            Transition::LargerThanLife { radius, middle, survives, borns, neighbourhood } =>
+                (radius as isize, middle, survives, borns, neighbourhood),
+            _ => unreachable!(),
+        };
+        let within = |dx: isize, dy: isize| match neighbourhood {
+            Neighbourhood::Moore => true,
+            Neighbourhood::VonNeumann => dx.abs() + dy.abs() <= radius,
+            // within radius+0.5
+            _ => dx*dx + dy*dy <= radius*radius + radius,
+        };
+        let (w, h) = (field.w as isize, field.h as isize);
+        let mut next = Field::new(field.w, field.h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut count = 0;
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        if !within(dx, dy) || (dx == 0 && dy == 0 && !middle) {
+                            continue;
+                        }
+                        let (nx, ny) = match topology {
+                            Topology::Plane => (x + dx, y + dy),
+                            Topology::Torus => ((x + dx).rem_euclid(w), (y + dy).rem_euclid(h)),
+                        };
+                        if nx >= 0 && ny >= 0 && nx < w && ny < h && field.get(nx as usize, ny as usize) {
+                            count += 1;
+                        }
+                    }
+                }
+                let (min, max) = if field.get(x as usize, y as usize) { survives } else { borns };
+                next.set(x as usize, y as usize, (min..=max).contains(&count));
+            }
+        }
+        next
+    }
+
+    #[test]
+    fn larger_than_life () {
+        for rulestring in [
+            "R2,C0,M0,S6..11,B7..9,NM", "R3,C0,M1,S14..25,B15..20,NM",
+            "R2,C0,M0,S3..6,B4..5,NN", "R3,C0,M1,S7..13,B8..10,NN",
+            "R2,C0,M0,S5..9,B6..8,NC", "R3,C0,M1,S12..21,B13..17,NC",
+        ] {
+            let rule = Rule::parse(rulestring).unwrap();
+            for topology in [Topology::Plane, Topology::Torus] {
+                for (w, h, threads) in [(19, 13, 1), (27, 7, 3)] {
+                    let field = random_field(w, h, w as u64);
+                    let mut backend = CpuBackend::new(threads, &rule, topology, w, h);
+                    backend.upload(&field);
+                    let mut expected = field.clone();
+                    let mut played = Field::new(w, h);
+                    for round in 1..=4 {
+                        backend.play(1);
+                        backend.download(&mut played);
+                        expected = ltl_reference(&rule, topology, &expected);
+                        assert!(
+                            played.data() == expected.data(),
+                            "{} on a {:?} of {}×{} cells: round {} differs", rulestring, topology, w, h, round
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
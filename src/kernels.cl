@@ -51,6 +51,23 @@ inline uchar work_byte (
     return result;
 }
 
+/*
+For the torus: the byte at x8 of a row that repeats itself every w bits.
+For x8=-1, bit 7 is the last cell of the row; for x8=w8-1, the bits past w
+are the first cells of the row; and x8=w8 continues from there.
+*/
+inline uchar torus_byte (__local uchar *row, uint w, long x8)
+{
+    uchar result = 0;
+    for (int bi=0; bi<8; bi++) {
+        long x = (x8*8 + bi) % (long)w;
+        if (x < 0)
+            x += w;
+        result |= ((row[x/8] >> (x%8)) & 1) << bi;
+    }
+    return result;
+}
+
 /*
 Plays Game Of Life or so in the row specified by y.
 */
@@ -71,6 +88,16 @@ __kernel void play (
             cutoff |= 1 << ci;
     }
     // fill the source buffer
+#if TORUS
+    // the rows above y=0 and below y=h-1 wrap around
+    const size_t y_t = y == 0 ? h-1 : y-1;
+    const size_t y_b = y == h-1 ? 0 : y+1;
+    for (size_t x8=0; x8<w8; x8++) {
+        source_buf[0*w8 + x8] = source[y_t*w8 + x8];
+        source_buf[1*w8 + x8] = source[y*w8 + x8];
+        source_buf[2*w8 + x8] = source[y_b*w8 + x8];
+    }
+#else
     if (y == 0) {
         for (size_t x8=0*w8; x8<1*w8; x8++) {
             source_buf[x8] = 0;
@@ -92,17 +119,49 @@ __kernel void play (
             source_buf[x8] = 0;
         }
     }
+#endif
     // source and target field operations ("t","m","b": top, mid and bottom rows)
     #define gt(x8) source_buf[x8]
     #define gm(x8) source_buf[w8 + x8]
     #define gb(x8) source_buf[2*w8 + x8]
     #define sm(x8, v) target[y*w8 + x8] = v
+#if TORUS
+    // the columns wrap around, too ("wt", "wm", "wb": see torus_byte)
+    #define wt(x8) torus_byte(source_buf, w, x8)
+    #define wm(x8) torus_byte(source_buf + w8, w, x8)
+    #define wb(x8) torus_byte(source_buf + 2*w8, w, x8)
+    // a single byte is both the left and the right edge
+    if (w8 == 1) {
+        sm(0, work_byte(
+            wt(-1), wt(0), wt(1),
+            wm(-1), wm(0), wm(1),
+            wb(-1), wb(0), wb(1)
+        ) & ~cutoff);
+        return;
+    }
+    // left edge
+    sm(0, work_byte(
+        wt(-1), gt(0), gt(1),
+        wm(-1), gm(0), gm(1),
+        wb(-1), gb(0), gb(1)
+    ));
+#else
+    // a single byte is both the left and the right edge
+    if (w8 == 1) {
+        sm(0, work_byte(
+            0, gt(0), 0,
+            0, gm(0), 0,
+            0, gb(0), 0
+        ) & ~cutoff);
+        return;
+    }
     // left edge
     sm(0, work_byte(
         0, gt(0), gt(1),
         0, gm(0), gm(1),
         0, gb(0), gb(1)
     ));
+#endif
     // mid
     for (size_t x8=1; x8<w8-1; x8++) {
         sm(x8, work_byte(
@@ -112,6 +171,19 @@ __kernel void play (
         ));
     }
     // right edge
+#if TORUS
+    sm(
+        w8 - 1,
+        work_byte(
+            gt(w8-2), wt(w8-1), wt(w8),
+            gm(w8-2), wm(w8-1), wm(w8),
+            gb(w8-2), wb(w8-1), wb(w8)
+        ) & ~cutoff
+    );
+    #undef wt
+    #undef wm
+    #undef wb
+#else
     sm(
         w8 - 1,
         work_byte(
@@ -120,8 +192,553 @@ __kernel void play (
             gb(w8-2), gb(w8-1), 0
         ) & ~cutoff
     );
+#endif
     #undef gt
     #undef gm
     #undef gb
     #undef sm
-}
\ No newline at end of file
+}
+
+/*
+Like torus_byte, for a row in global memory.
+*/
+inline uchar torus_byte_global (__global uchar *row, uint w, long x8)
+{
+    uchar result = 0;
+    for (int bi=0; bi<8; bi++) {
+        long x = (x8*8 + bi) % (long)w;
+        if (x < 0)
+            x += w;
+        result |= ((row[x/8] >> (x%8)) & 1) << bi;
+    }
+    return result;
+}
+
+/*
+Copies the tile_w×tile_h bytes from (x8_0, y_0) on into tile, every work item
+of the work group a share of them. On the plane, the bytes beyond the edges
+are 0; on the torus, byte x8 holds the cells x8*8, x8*8+1, ... of the row,
+each modulo w (see torus_byte), and row y is row y modulo h.
+*/
+inline void load_tile (
+        uint w,
+        uint h,
+        __global uchar *source,
+        __local uchar *tile,
+        int x8_0,
+        int y_0,
+        int tile_w,
+        int tile_h
+)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int size = get_local_size(0)*get_local_size(1);
+    for (int i=get_local_id(1)*get_local_size(0) + get_local_id(0); i<tile_w*tile_h; i+=size) {
+        const int x8 = x8_0 + i%tile_w;
+        int y = y_0 + i/tile_w;
+        uchar b = 0;
+#if TORUS
+        // the rows wrap around, and so do the columns (see torus_byte)
+        y = ((y % (int)h) + (int)h) % (int)h;
+        if (x8 >= 0 && (x8 < w8-1 || (x8 == w8-1 && w%8 == 0)))
+            b = source[y*w8 + x8];
+        else
+            b = torus_byte_global(source + y*w8, w, x8);
+#else
+        if (y >= 0 && y < (int)h && x8 >= 0 && x8 < w8)
+            b = source[y*w8 + x8];
+#endif
+        tile[i] = b;
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+}
+
+/*
+Plays Game Of Life or so like "play", but with a work item for every byte
+(x8, y) of the field. First, the work group copies its tile of bytes, with a
+halo of one byte (and row) around it, into local memory; then every work
+item works on its byte from there. The tile is as large as the work group
+(-> "tile" in BackendKind), and the global size may be rounded up to a
+multiple of it.
+*/
+__kernel void play_tiled (
+        uint w,
+        uint h,
+        __global uchar *source,
+        __global uchar *target,
+        __local uchar *tile
+)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int lx = get_local_id(0);
+    const int ly = get_local_id(1);
+    const int tw = get_local_size(0);
+    const int th = get_local_size(1);
+    // the tile with its halo, from its top left corner
+    const int tile_w = tw + 2;
+    load_tile(w, h, source, tile, get_group_id(0)*tw - 1, get_group_id(1)*th - 1, tile_w, th + 2);
+    const int x8 = get_global_id(0);
+    const int y = get_global_id(1);
+    if (x8 >= w8 || y >= (int)h)
+        return;
+    // the bytes around (x8, y) in the tile
+    #define tb(dx, dy) tile[(ly+1+dy)*tile_w + lx+1+dx]
+    uchar result = work_byte(
+        tb(-1, -1), tb(0, -1), tb(1, -1),
+        tb(-1, 0), tb(0, 0), tb(1, 0),
+        tb(-1, 1), tb(0, 1), tb(1, 1)
+    );
+    #undef tb
+    // clear the bits past w
+    if (x8 == w8-1 && w%8 != 0)
+        result &= (1 << (w%8)) - 1;
+    target[y*w8 + x8] = result;
+}
+
+/*
+Like "play_tiled", but plays k rounds (up to BLOCK_ROUNDS) at once: the halo
+is BLOCK_ROUNDS rows high and wide enough for BLOCK_ROUNDS cells, and the
+work group plays the whole tile with its halo in local memory, from tile
+into next and back, k times. The bytes beyond the tile are taken as 0, which
+is wrong, but the mistake creeps inwards by just one cell per round, so it
+never reaches the middle, which is written back in the end. On the plane,
+the cells beyond the edges are cleared after every round.
+*/
+__kernel void play_blocked (
+        uint w,
+        uint h,
+        uint k,
+        __global uchar *source,
+        __global uchar *target,
+        __local uchar *tile,
+        __local uchar *next
+)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int lx = get_local_id(0);
+    const int ly = get_local_id(1);
+    const int tw = get_local_size(0);
+    const int th = get_local_size(1);
+    // the halo in bytes and rows
+    const int halo_x8 = (BLOCK_ROUNDS + 7) / 8;
+    const int halo_y = BLOCK_ROUNDS;
+    const int tile_w = tw + 2*halo_x8;
+    const int tile_h = th + 2*halo_y;
+    const int x8_0 = get_group_id(0)*tw - halo_x8;
+    const int y_0 = get_group_id(1)*th - halo_y;
+    load_tile(w, h, source, tile, x8_0, y_0, tile_w, tile_h);
+    // the bytes around (tx, ty) in the tile, 0 beyond it
+    #define tb(dx, dy) ( \
+        tx+dx >= 0 && tx+dx < tile_w && ty+dy >= 0 && ty+dy < tile_h \
+            ? tile[(ty+dy)*tile_w + tx+dx] : 0 \
+    )
+    for (uint round=0; round<k; round++) {
+        for (int i=ly*tw + lx; i<tile_w*tile_h; i+=tw*th) {
+            const int tx = i%tile_w;
+            const int ty = i/tile_w;
+            uchar result = work_byte(
+                tb(-1, -1), tb(0, -1), tb(1, -1),
+                tb(-1, 0), tb(0, 0), tb(1, 0),
+                tb(-1, 1), tb(0, 1), tb(1, 1)
+            );
+#if !TORUS
+            const int x8 = x8_0 + tx;
+            const int y = y_0 + ty;
+            if (y < 0 || y >= (int)h || x8 < 0 || x8 >= w8)
+                result = 0;
+            else if (x8 == w8-1 && w%8 != 0)
+                result &= (1 << (w%8)) - 1;
+#endif
+            next[i] = result;
+        }
+        barrier(CLK_LOCAL_MEM_FENCE);
+        __local uchar *swap = tile;
+        tile = next;
+        next = swap;
+    }
+    #undef tb
+    const int x8 = get_global_id(0);
+    const int y = get_global_id(1);
+    if (x8 >= w8 || y >= (int)h)
+        return;
+    uchar result = tile[(ly+halo_y)*tile_w + lx+halo_x8];
+    // clear the bits past w (on the torus, they hold the first cells of the row)
+    if (x8 == w8-1 && w%8 != 0)
+        result &= (1 << (w%8)) - 1;
+    target[y*w8 + x8] = result;
+}
+
+#if SLICED
+/*
+For "play_sliced": cell x of a row (on the torus, cell x modulo w; on the
+plane, 0 beyond the edges), and the 32 cells from x32*32 on, in a uint.
+*/
+inline uint sliced_cell (__global uchar *row, int w, int x)
+{
+#if TORUS
+    x = ((x % w) + w) % w;
+#else
+    if (x < 0 || x >= w)
+        return 0;
+#endif
+    return (row[x/8] >> (x%8)) & 1;
+}
+
+inline uint sliced_word (__global uchar *row, int w, int x32)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+    uint word = 0;
+#if TORUS
+    // the last word goes on with the first cells of the row
+    if ((x32+1)*32 > w) {
+        for (int bi=0; bi<32; bi++)
+            word |= sliced_cell(row, w, x32*32 + bi) << bi;
+        return word;
+    }
+#endif
+    for (int i=0; i<4 && x32*4+i < w8; i++)
+        word |= (uint)row[x32*4 + i] << (i*8);
+    return word;
+}
+
+/*
+The left neighbours (bit i: cell i-1), the cells themselves and the right
+neighbours (bit i: cell i+1) of 32 cells in row y.
+*/
+inline void sliced_row (
+        __global uchar *source,
+        int w,
+        int h,
+        int x32,
+        int y,
+        uint *left,
+        uint *middle,
+        uint *right
+)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+#if TORUS
+    y = ((y % h) + h) % h;
+#else
+    if (y < 0 || y >= h) {
+        *left = *middle = *right = 0;
+        return;
+    }
+#endif
+    __global uchar *row = source + y*w8;
+    *middle = sliced_word(row, w, x32);
+    *left = (*middle << 1) | sliced_cell(row, w, x32*32 - 1);
+    *right = (*middle >> 1) | (sliced_cell(row, w, x32*32 + 32) << 31);
+}
+
+/*
+Plays an outer totalistic rule (SLICED_BORNS, SLICED_SURVIVES: bitmasks over
+the counts 0..8) on 32 cells at once, one uint (x32, y) per work item: the 8
+neighbours are added up bit by bit, with full adders on whole words, into the
+bits s0..s3 of the count of every cell.
+*/
+__kernel void play_sliced (
+        uint w,
+        uint h,
+        __global uchar *source,
+        __global uchar *target
+)
+{
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int x32 = get_global_id(0);
+    const int y = get_global_id(1);
+    if (x32*32 >= (int)w || y >= (int)h)
+        return;
+    uint tl, tm, tr, ml, mm, mr, bl, bm, br;
+    sliced_row(source, w, h, x32, y-1, &tl, &tm, &tr);
+    sliced_row(source, w, h, x32, y, &ml, &mm, &mr);
+    sliced_row(source, w, h, x32, y+1, &bl, &bm, &br);
+    // the sums (a, b, c) and carries (a2, b2, c2) of 3, 3 and 2 neighbours
+    #define full_adder(s, c, x, y, z) s = x ^ y ^ z; c = (x & y) | (z & (x ^ y))
+    uint a, a2, b, b2, s0, d2, e4, f4;
+    full_adder(a, a2, tl, tm, tr);
+    full_adder(b, b2, ml, mr, bl);
+    const uint c = bm ^ br;
+    const uint c2 = bm & br;
+    // bit 0 of the count, and a carry to bit 1
+    full_adder(s0, d2, a, b, c);
+    // the carries: bit 1 of the count, and carries to bit 2
+    uint t;
+    full_adder(t, e4, a2, b2, c2);
+    const uint s1 = t ^ d2;
+    f4 = t & d2;
+    const uint s2 = e4 ^ f4;
+    const uint s3 = e4 & f4;
+    #undef full_adder
+    uint result = 0;
+    for (int count=0; count<=8; count++) {
+        const uint is_count =
+            (count & 1 ? s0 : ~s0) & (count & 2 ? s1 : ~s1) &
+            (count & 4 ? s2 : ~s2) & (count & 8 ? s3 : ~s3);
+        if ((SLICED_BORNS >> count) & 1)
+            result |= is_count & ~mm;
+        if ((SLICED_SURVIVES >> count) & 1)
+            result |= is_count & mm;
+    }
+    // clear the bits past w
+    if ((x32+1)*32 > (int)w)
+        result &= (1u << (w%32)) - 1;
+    for (int i=0; i<4 && x32*4+i < w8; i++)
+        target[y*w8 + x32*4 + i] = (result >> (i*8)) & 0xff;
+}
+#endif
+
+/*
+Adds up the sums of a work group in local memory, and writes the total to
+partial; the host adds those up (-> reduce in opencl.rs).
+*/
+inline void reduce (ulong sum, __local ulong *sums, __global ulong *partial)
+{
+    const size_t lid = get_local_id(0);
+    sums[lid] = sum;
+    barrier(CLK_LOCAL_MEM_FENCE);
+    // the local size is a power of 2
+    for (size_t half=get_local_size(0)/2; half>0; half/=2) {
+        if (lid < half)
+            sums[lid] += sums[lid + half];
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+    if (lid == 0)
+        partial[get_group_id(0)] = sums[0];
+}
+
+/*
+Counts the live cells of a field: the set bits, or for Generations rules, the
+cells in state 1. Every work item sums a strided share of the bytes.
+*/
+__kernel void population (
+        __global uchar *field,
+        ulong size,
+        __global ulong *partial,
+        __local ulong *sums
+)
+{
+    ulong sum = 0;
+    for (size_t i=get_global_id(0); i<size; i+=get_global_size(0)) {
+#if STATES > 2
+        sum += field[i] == 1;
+#else
+        sum += popcount(field[i]);
+#endif
+    }
+    reduce(sum, sums, partial);
+}
+
+/*
+Mixes the bits of x (the finalizer of SplitMix64); not named "mix", which is
+an OpenCL built-in.
+*/
+inline ulong splitmix64 (ulong x)
+{
+    x ^= x >> 30;
+    x *= 0xbf58476d1ce4e5b9UL;
+    x ^= x >> 27;
+    x *= 0x94d049bb133111ebUL;
+    x ^= x >> 31;
+    return x;
+}
+
+/*
+Hashes a field (or its states): the sum of the mixed index and value of every
+byte that isn't 0. Being a sum, it doesn't depend on the order in which the
+work items get to the bytes (-> hash in cpu.rs).
+*/
+__kernel void hash (
+        __global uchar *field,
+        ulong size,
+        __global ulong *partial,
+        __local ulong *sums
+)
+{
+    ulong sum = 0;
+    for (size_t i=get_global_id(0); i<size; i+=get_global_size(0)) {
+        if (field[i] != 0)
+            sum += splitmix64(((ulong)i << 8) | field[i]);
+    }
+    reduce(sum, sums, partial);
+}
+
+/*
+The cells from a up to b of a block of a grid (-> density), as start and end
+(exclusive); a block smaller than a cell takes the cell at its centre.
+*/
+inline int2 block_range (float a, float b)
+{
+    int2 range = (int2)(floor(a), floor(b));
+    if (range.y <= range.x) {
+        range.x = floor((a + b) / 2);
+        range.y = range.x + 1;
+    }
+    return range;
+}
+
+/*
+The share of live cells in every block of a grid over the field, as a grey
+level (0-255) per block (-> Blocks in backend.rs). Block (bx, by) covers the
+rows from y0 + by*block_h, and in row y, the cells from x0 + bx*block_w +
+y*shear; the cells outside the field count as dead.
+*/
+__kernel void density (
+        uint w,
+        uint h,
+        __global uchar *field,
+        float x0,
+        float y0,
+        float block_w,
+        float block_h,
+        float shear,
+        __global uchar *out
+)
+{
+    const int bx = get_global_id(0);
+    const int by = get_global_id(1);
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int2 ys = block_range(y0 + by*block_h, y0 + (by+1)*block_h);
+    ulong live = 0;
+    ulong area = 0;
+    for (int y=ys.x; y<ys.y; y++) {
+        const float a = x0 + bx*block_w + y*shear;
+        const int2 xs = block_range(a, a + block_w);
+        area += xs.y - xs.x;
+        if (y < 0 || y >= (int)h)
+            continue;
+        int x = max(xs.x, 0);
+        const int x_end = min(xs.y, (int)w);
+#if STATES > 2
+        for (; x<x_end; x++)
+            live += field[y*w + x] == 1;
+#else
+        __global uchar *row = field + y*w8;
+        // single cells up to a whole byte, whole bytes, single cells again
+        for (; x<x_end && x%8; x++)
+            live += (row[x/8] >> (x%8)) & 1;
+        for (; x+8<=x_end; x+=8)
+            live += popcount(row[x/8]);
+        for (; x<x_end; x++)
+            live += (row[x/8] >> (x%8)) & 1;
+#endif
+    }
+    out[by*get_global_size(0) + bx] = (255*live + area/2) / area;
+}
+
+/*
+Is the cell alive in the next generation? For Generations rules, with the
+3×3 neighbourhood numbered as in rule.rs (NEXT is baked in in opencl.rs).
+*/
+inline uchar next_alive (ushort env) {
+    return (NEXT[env/8] >> (env%8)) & 1;
+}
+
+/*
+Plays a Generations rule (see rule.rs) for the cell at (x, y).
+Unlike "play", source and target have one byte (the state) per cell.
+*/
+__kernel void play_generations (
+        uint w,
+        uint h,
+        __global uchar *source,
+        __global uchar *target
+)
+{
+    int x = get_global_id(0);
+    int y = get_global_id(1);
+    // the neighbours in state 1
+    ushort env = 0;
+    for (int dy=-1; dy<=1; dy++) {
+        for (int dx=-1; dx<=1; dx++) {
+            int nx = x + dx;
+            int ny = y + dy;
+#if TORUS
+            nx = (nx + (int)w) % (int)w;
+            ny = (ny + (int)h) % (int)h;
+#else
+            if (nx < 0 || ny < 0 || nx >= (int)w || ny >= (int)h)
+                continue;
+#endif
+            if (source[ny*w + nx] == 1)
+                env |= 1 << ((dy+1)*3 + dx+1);
+        }
+    }
+    // see Rule::next_state
+    uchar state = source[y*w + x];
+    uchar next;
+    if (state == 0)
+        next = next_alive(env);
+    else if (state == 1 && next_alive(env))
+        next = 1;
+    else if (state + 1 >= STATES)
+        next = 0;
+    else
+        next = state + 1;
+    target[y*w + x] = next;
+}
+
+#if LTL_RADIUS
+/*
+For Larger-than-Life rules: the cell at x of a row, or 0 (or the cell on the
+other side, for the torus) if x is beyond the edges.
+*/
+inline uint ltl_cell (__global uchar *row, int w, int x)
+{
+#if TORUS
+    x = ((x % w) + w) % w;
+#else
+    if (x < 0 || x >= w)
+        return 0;
+#endif
+    return (row[x/8] >> (x%8)) & 1;
+}
+
+/*
+Plays a Larger-than-Life rule (see rule.rs) for the 8×1 slice at (x8, y).
+The neighbourhood reaches EXTENT[dy+LTL_RADIUS] cells left and right in the
+row dy; along the slice, the count of each row slides from cell to cell.
+*/
+__kernel void play_ltl (
+        uint w,
+        uint h,
+        __global uchar *source,
+        __global uchar *target
+)
+{
+    int x8 = get_global_id(0);
+    int y = get_global_id(1);
+    const int w8 = w%8 ? w/8+1 : w/8;
+    const int x0 = x8*8;
+    // the counts of the 8 cells, with the cells themselves
+    uint counts[8] = {0, 0, 0, 0, 0, 0, 0, 0};
+    for (int dy=-LTL_RADIUS; dy<=LTL_RADIUS; dy++) {
+        int ny = y + dy;
+#if TORUS
+        ny = ((ny % (int)h) + (int)h) % (int)h;
+#else
+        if (ny < 0 || ny >= (int)h)
+            continue;
+#endif
+        __global uchar *row = source + ny*w8;
+        const int extent = EXTENT[dy + LTL_RADIUS];
+        uint count = 0;
+        for (int nx=x0-extent; nx<=x0+extent; nx++)
+            count += ltl_cell(row, w, nx);
+        counts[0] += count;
+        for (int bi=1; bi<8; bi++) {
+            count += ltl_cell(row, w, x0+bi+extent);
+            count -= ltl_cell(row, w, x0+bi-1-extent);
+            counts[bi] += count;
+        }
+    }
+    uchar result = 0;
+    for (int bi=0; bi<8 && x0+bi<(int)w; bi++) {
+        uint alive = ltl_cell(source + y*w8, w, x0+bi);
+        uint count = counts[bi] - (LTL_MIDDLE ? 0 : alive);
+        if (alive ? (count >= S_MIN && count <= S_MAX) : (count >= B_MIN && count <= B_MAX))
+            result |= 1 << bi;
+    }
+    target[y*w8 + x8] = result;
+}
+#endif
@@ -0,0 +1,38 @@
+//! simple_cells as a library: the field, the rule and the automata that
+//! plays the rounds, either on an OpenCL GPU or on the CPU.
+//!
+//! ```no_run
+//! extern crate simple_cells;
+//! use simple_cells::automata::{Automata, Field, Topology};
+//! use simple_cells::backend::BackendKind;
+//! use simple_cells::rule::Rule;
+//!
+//! let mut seed = Field::new(64, 64);
+//! seed.set(10, 10, true);
+//! seed.set(11, 10, true);
+//! seed.set(12, 10, true);
+//! let rule = Rule::parse("B3/S23").unwrap();
+//! let mut automata = Automata::with_rule(
+//!     &BackendKind::Cpu { threads: 0 },
+//!     &rule,
+//!     Topology::Plane,
+//!     seed,
+//! ).unwrap();
+//! automata.play(1);
+//! assert!(automata.get(11, 9));
+//! ```
+
+extern crate json;
+#[cfg(feature = "png")]
+extern crate sdl2;
+
+pub mod automata;
+pub mod backend;
+pub mod checkpoint;
+mod cpu;
+#[cfg(feature = "opencl")]
+mod opencl;
+pub mod pattern;
+#[cfg(feature = "png")]
+pub mod png;
+pub mod rule;
@@ -1,10 +1,19 @@
 extern crate chrono;
+extern crate json;
 extern crate sdl2;
+extern crate simple_cells;
 
-mod automata;
 mod utils;
 mod window;
 
+use simple_cells::automata;
+use simple_cells::backend;
+use simple_cells::checkpoint;
+use simple_cells::pattern;
+use simple_cells::png;
+use simple_cells::rule;
+
+use std::io::Write;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -15,15 +24,56 @@ fn main ()
             & std::fs::read_to_string("prefs.json")
                 .expect("Please ChDir to the path with the seed files and prefs.json.")
         ).unwrap();
-    let seed_json = json::parse(
-            & std::fs::read_to_string("seed.json")
+    // "--resume <dir>": carry on from the latest snapshot in dir
+    // "--benchmark <rounds>": time the OpenCL kernels on the seed, and quit
+    let (resume_dir, benchmark_rounds) = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!("Usage: simple_cells [--resume <snapshots dir>] [--benchmark <rounds>]");
+        std::process::exit(2);
+    });
+    let seed_json_path = match resume_dir {
+        Some(ref dir) => format!("{}/seed.json", dir),
+        None => String::from("seed.json"),
+    };
+    let mut seed_json = json::parse(
+            & std::fs::read_to_string(&seed_json_path)
                 .expect("Please ChDir to the path with the seed files and prefs.json.")
         ).unwrap();
-    let mut window = window::Window::new(&prefs_json);
-    let gpu_i = prefs_json["gpu_i"].as_usize().unwrap();
-    let mut automata = automata::Automata::new(&window, gpu_i, &seed_json).unwrap();
+    let _sdl_img_context = sdl2::image::init(sdl2::image::InitFlag::PNG).unwrap();
+    let backend_kind = backend::BackendKind::from_prefs(&prefs_json).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let (seed, mut n) = match resume_dir {
+        Some(ref dir) => load_latest_snapshot(dir, &mut seed_json),
+        None => load_seed(&mut seed_json).and_then(|seed| {
+            let n = seed_json["n"].as_usize().ok_or("seed.json lacks \"n\" (the generation of the seed).")?;
+            Ok((seed, n))
+        }),
+    }.unwrap_or_else(|err| {
+        eprintln!("Cannot load the seed: {}", err);
+        std::process::exit(1);
+    });
+    if let Some(rounds) = benchmark_rounds {
+        benchmark(&prefs_json, &seed_json, seed, rounds).unwrap_or_else(|err| {
+            eprintln!("Benchmark failed: {}", err);
+            std::process::exit(1);
+        });
+        return;
+    }
+    // headless: no SDL video at all, e.g. for servers without a display
+    let headless = prefs_json["headless"].as_bool().unwrap_or(false);
+    let mut window = if headless {
+        None
+    } else {
+        Some(window::Window::new(&prefs_json))
+    };
+    // a bad rulestring or topology, or a torus too small for the rule
+    let mut automata = automata::Automata::new(&backend_kind, &seed_json, seed).unwrap_or_else(|err| {
+        eprintln!("Cannot start the automata: {}", err);
+        std::process::exit(1);
+    });
     let (w, h) = (automata.w, automata.h);
-    let mut n = seed_json["n"].as_usize().unwrap();
     let mut rpf = 1_f64; //playing rounds per frame
     let mut t_counter = Instant::now();
     let mut f_counter = 0_usize;
@@ -31,56 +81,108 @@ fn main ()
     let /*const*/ second: Duration = Duration::new(1, 0);
     let fps = prefs_json["fps"].as_f64().unwrap();
     let snapshots = prefs_json["snapshots"].as_isize().unwrap();
-    let snapshots_dir = chrono::Local::now().format("%y%m%d.%H%M%S");
-    let snapshots_dir = format!("{}", snapshots_dir);
-    if snapshots > 0 {
+    let snapshots_dir = match resume_dir {
+        Some(ref dir) => dir.clone(),
+        None => format!("{}", chrono::Local::now().format("%y%m%d.%H%M%S")),
+    };
+    let population_csv = prefs_json["population_csv"].as_bool().unwrap_or(false);
+    // the number of generations in which to look for repetitions
+    automata.detect_periods(prefs_json["detect_period"].as_usize().unwrap_or(0));
+    let stop_on_period = prefs_json["stop_on_period"].as_bool().unwrap_or(false);
+    let mut period = None;
+    // a resumed run already has its seed.json and its latest snapshot
+    if (snapshots > 0 || population_csv || stop_on_period) && resume_dir.is_none() {
         std::fs::create_dir(&snapshots_dir).unwrap();
-        std::fs::copy(
-            "seed.json",
-            &format!("{}/seed.json", &snapshots_dir)
-        ).unwrap();
-        std::fs::copy(
-            "seed.png",
-            &format!("{}/{:020}.png", &snapshots_dir, n)
+        // with the rulestring of the pattern file, if it came from there
+        std::fs::write(
+            format!("{}/seed.json", &snapshots_dir),
+            seed_json.pretty(4)
         ).unwrap();
+        if snapshots > 0 {
+            snapshot(&mut automata, &snapshots_dir, n, &prefs_json);
+        }
     }
-    let mut snapshot_counter = 0_f64;
-    let mut snapshot_trigger = false;
-    let mut snapshot_restore_rpf = 1_f64;
+    let mut population_log = if population_csv {
+        Some(open_population_csv(&snapshots_dir, n, &mut automata).unwrap())
+    } else {
+        None
+    };
+    let mut snapshot_counter = 0_usize;
     let benchmark_print = prefs_json["benchmark_print"].as_f64().unwrap();
     let mut benchmark_counter = 0;
     let mut benchmark_t = Instant::now();
 
     loop
     {
-        window.present(&automata);
+        if let Some(ref mut window) = window {
+            window.present(&mut automata);
+            if window.exit_issued {
+                break;
+            }
+        }
         f_counter += 1;
-        if window.exit_issued {
-            break;
+
+        // the rounds of this frame: as many as "fps" allows, or as chosen in the window
+        let rounds = match window {
+            Some(ref mut window) => window.rounds(rpf as usize),
+            None => rpf as usize,
+        };
+        // in parts that end where a snapshot is due
+        let mut rounds_left = rounds;
+        while rounds_left > 0 {
+            let part = if snapshots > 0 {
+                rounds_left.min(snapshots as usize - snapshot_counter)
+            } else {
+                rounds_left
+            };
+            if let Some(ref mut population_log) = population_log {
+                let n0 = n;
+                let mut i = 0;
+                automata.play_counting(part, |population| {
+                    i += 1;
+                    writeln!(population_log, "{},{}", n0 + i, population).unwrap();
+                });
+            } else {
+                automata.play(part);
+            }
+            n += part;
+            snapshot_counter += part;
+            rounds_left -= part;
+            if snapshots > 0 && snapshot_counter == snapshots as usize {
+                snapshot(&mut automata, &snapshots_dir, n, &prefs_json);
+                // so that a resumed run finds the log up to its snapshot
+                if let Some(ref mut population_log) = population_log {
+                    population_log.flush().unwrap();
+                }
+                snapshot_counter = 0;
+            }
         }
+        r_counter += rounds as isize;
+        benchmark_counter += rounds;
 
-        if snapshots > 0 {
-            if snapshot_counter+rpf >= snapshots as f64 {
-                snapshot_trigger = true;
-                snapshot_restore_rpf = rpf;
-                rpf = snapshots as f64 - snapshot_counter;
+        if automata.period() != period {
+            period = automata.period();
+            match period {
+                Some(1) => println!("The pattern is static at n={}.", n),
+                Some(p) => println!("The pattern is periodic at n={}, with period {}.", n, p),
+                None => println!("The pattern is no longer periodic at n={}.", n),
+            }
+            if period.is_some() && stop_on_period {
+                snapshot(&mut automata, &snapshots_dir, n, &prefs_json);
+                break;
             }
         }
 
-        automata.play(rpf as usize);
-        n += rpf as usize;
-        snapshot_counter += rpf as usize as f64;
-        r_counter += rpf as isize;
-        benchmark_counter += rpf as usize;
-
-        if snapshot_trigger {
-            window.snapshot_png(
-                &automata,
-                &format!("{}/{:020}.png", &snapshots_dir, n)
-            );
-            snapshot_trigger = false;
-            snapshot_counter = 0.0;
-            rpf = snapshot_restore_rpf;
+        if let Some(ref mut window) = window {
+            window.show_status(n, period);
+            // paused: no measuring, and no need to hurry
+            if window.paused {
+                std::thread::sleep(Duration::from_secs_f64(1.0 / fps));
+                t_counter = Instant::now();
+                f_counter = 0;
+                r_counter = 0;
+                continue;
+            }
         }
 
         let elapsed = t_counter.elapsed();
@@ -111,4 +213,233 @@ fn main ()
 
     }
 
+    if let Some(ref mut population_log) = population_log {
+        population_log.flush().unwrap();
+    }
+
+}
+
+/*
+The seed field: "seed" in seed.json names a PNG (the default: "seed.png") or a
+pattern file (".rle", ".mc" or ".cells"). A pattern is put into a field of
+"w"×"h" cells (default: the size of the pattern), with its top left corner at
+"x", "y", or centred if these are missing. Its rulestring is used if seed.json
+has none.
+*/
+fn load_seed (seed_json: &mut json::JsonValue) -> Result<automata::Field, Box<dyn std::error::Error>>
+{
+    let path = seed_json["seed"].as_str().unwrap_or("seed.png").to_string();
+    // like Pattern::load, regardless of case
+    if path.to_ascii_lowercase().ends_with(".png") {
+        return png::seed_png(&path, &png::SeedOptions::from_seed(seed_json)?);
+    }
+    let placement = match (seed_json["x"].as_isize(), seed_json["y"].as_isize()) {
+        (Some(x), Some(y)) => pattern::Placement::At(x, y),
+        _ => pattern::Placement::Centred,
+    };
+    // with the field's size known, huge macrocell patterns are only expanded as far as needed
+    let pattern = match (seed_json["w"].as_usize(), seed_json["h"].as_usize()) {
+        (Some(w), Some(h)) => pattern::Pattern::load_within(&path, w, h, placement)?,
+        _ => pattern::Pattern::load(&path)?,
+    };
+    if seed_json["rulestring"].is_null() {
+        if let Some(rule) = pattern.rule {
+            seed_json["rulestring"] = rule.to_string().into();
+        }
+    }
+    Ok(pattern.to_field(
+        seed_json["w"].as_usize().unwrap_or(pattern.w),
+        seed_json["h"].as_usize().unwrap_or(pattern.h),
+        placement,
+    ))
+}
+
+fn parse_args () -> Result<(Option<String>, Option<usize>), String>
+{
+    let mut resume_dir = None;
+    let mut benchmark_rounds = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => {
+                let dir = args.next().ok_or("--resume needs a snapshots directory.")?;
+                resume_dir = Some(dir.trim_end_matches('/').to_string());
+            },
+            "--benchmark" => {
+                let rounds = args.next().and_then(|rounds| rounds.parse::<usize>().ok())
+                    .ok_or("--benchmark needs a number of rounds.")?;
+                benchmark_rounds = Some(rounds);
+            },
+            _ => return Err(format!("Unknown argument \"{}\".", arg)),
+        }
+    }
+    Ok((resume_dir, benchmark_rounds))
+}
+
+/*
+Plays the seed for some rounds on the OpenCL GPU of prefs.json, once with
+the kernel with a work item per row, once with the tiled one (with the tile
+size of prefs.json, or 16×16) and, with "block_rounds" above 1, once with the
+tiled one that plays that many rounds per launch, and for outer totalistic
+rules once with the bit-sliced one; prints how fast each was, and checks
+that all ended up with the same field. Only 2-state rules up to radius 1
+have tiled kernels.
+*/
+fn benchmark (prefs_json: &json::JsonValue, seed_json: &json::JsonValue, seed: automata::Field, rounds: usize) -> Result<(), Box<dyn std::error::Error>>
+{
+    let (gpu_i, kernels) = match backend::BackendKind::from_prefs(prefs_json)? {
+        backend::BackendKind::OpenCl { gpu_i, kernels } => (gpu_i, kernels),
+        backend::BackendKind::Cpu { .. } => return Err(Box::new(automata::AutomataError::new(
+            "The benchmark compares the OpenCL kernels; please set \"backend\": \"opencl\"."
+        ))),
+    };
+    let rule = rule::Rule::parse(seed_json["rulestring"].as_str().unwrap_or(""))?;
+    if rule.states > 2 || rule.radius() > 1 {
+        println!("The rule has no tiled kernel.");
+        return Ok(());
+    }
+    let tile = kernels.tile.unwrap_or((16, 16));
+    let lookup = |tile, block_rounds| backend::ClKernels { tile, block_rounds, sliced: false };
+    let mut runs = vec![
+        (String::from("rows"), lookup(None, 1)),
+        (format!("tiled ({}×{})", tile.0, tile.1), lookup(Some(tile), 1)),
+    ];
+    if kernels.block_rounds > 1 {
+        runs.push((
+            format!("blocked ({}×{}, {} rounds per launch)", tile.0, tile.1, kernels.block_rounds),
+            lookup(Some(tile), kernels.block_rounds),
+        ));
+    }
+    if rule.outer_totalistic().is_some() {
+        runs.push((String::from("bit-sliced"), backend::ClKernels { tile: None, block_rounds: 1, sliced: true }));
+    }
+    let (w, h) = (seed.w, seed.h);
+    let mut fields = Vec::new();
+    for (name, kernels) in runs {
+        let backend_kind = backend::BackendKind::OpenCl { gpu_i, kernels };
+        let mut automata = automata::Automata::new(&backend_kind, seed_json, seed.clone())?;
+        // uploads the seed, and leaves out the first round
+        automata.play(1);
+        let t = Instant::now();
+        automata.play(rounds);
+        let elapsed = t.elapsed().as_secs_f64();
+        print!("{}, {} rounds in {:.3} s: ", name, rounds, elapsed);
+        utils::benchmark_print((rounds*w*h) as f64, elapsed);
+        fields.push(automata.field().clone());
+    }
+    if fields.iter().any(|field| field.data() != fields[0].data()) {
+        return Err(Box::new(automata::AutomataError::new(
+            "The kernels ended up with different fields."
+        )));
+    }
+    Ok(())
+}
+
+/*
+"population.csv" in the snapshots directory, with the population of every
+generation n ("n,population" per line), ready for appending from n on.
+When resuming, the lines after n are dropped, as those generations are
+played again.
+*/
+fn open_population_csv (snapshots_dir: &str, n: usize, automata: &mut automata::Automata) -> std::io::Result<std::io::BufWriter<std::fs::File>>
+{
+    let path = format!("{}/population.csv", snapshots_dir);
+    let mut csv = String::from("n,population\n");
+    if let Ok(old_csv) = std::fs::read_to_string(&path) {
+        let lines = old_csv.lines().skip(1).filter(|line| {
+            line.split(',').next().and_then(|line_n| line_n.parse::<usize>().ok())
+                .is_some_and(|line_n| line_n < n)
+        });
+        for line in lines {
+            csv.push_str(line);
+            csv.push('\n');
+        }
+    }
+    csv.push_str(&format!("{},{}\n", n, automata.population()));
+    std::fs::write(&path, csv)?;
+    let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+    Ok(std::io::BufWriter::new(file))
+}
+
+/*
+The snapshot with the greatest n in a snapshots directory (see "snapshot"),
+and that n. Of <n>.ckpt, <n>.rle and <n>.png, the first one found is taken:
+a checkpoint restores the run exactly, including its rule and topology, and
+RLE keeps the states of Generations rules, unlike PNG.
+*/
+fn load_latest_snapshot (dir: &str, seed_json: &mut json::JsonValue) -> Result<(automata::Field, usize), Box<dyn std::error::Error>>
+{
+    // (n, preference, path)
+    let mut latest: Option<(usize, u8, std::path::PathBuf)> = None;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (stem, extension) = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) => (stem.to_string_lossy(), extension.to_string_lossy()),
+            _ => continue,
+        };
+        let preference = match extension.as_ref() {
+            "ckpt" => 2,
+            "rle" => 1,
+            "png" => 0,
+            _ => continue,
+        };
+        let n = match stem.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        if latest.as_ref().is_none_or(|&(latest_n, latest_preference, _)| (n, preference) > (latest_n, latest_preference)) {
+            latest = Some((n, preference, path));
+        }
+    }
+    let (n, preference, path) = latest.ok_or_else(|| automata::AutomataError::new(
+        &format!("There are no snapshots in \"{}\".", dir)
+    ))?;
+    let path = path.to_string_lossy();
+    println!("Resuming from {}", path);
+    let field = match preference {
+        2 => {
+            let checkpoint = checkpoint::load(&path)?;
+            seed_json["rulestring"] = checkpoint.rule.to_string().into();
+            seed_json["topology"] = checkpoint.topology.to_string().into();
+            return Ok((checkpoint.field, checkpoint.n));
+        },
+        1 => {
+            let pattern = pattern::Pattern::load(&path)?;
+            pattern.to_field(pattern.w, pattern.h, pattern::Placement::At(0, 0))
+        },
+        _ => {
+            let rulestring = seed_json["rulestring"].as_str().unwrap_or("");
+            png::load_snapshot_png(&path, rule::Rule::parse(rulestring)?.states)?
+        },
+    };
+    Ok((field, n))
+}
+
+/*
+<n>.png, unless "snapshot_png" in prefs.json is false (which saves the
+conversion for huge fields), <n>.rle if "snapshot_rle" is set, and the
+checkpoint <n>.ckpt if "snapshot_checkpoint" is set, compressed unless
+"checkpoint_compress" is false.
+*/
+fn snapshot (automata: &mut automata::Automata, snapshots_dir: &str, n: usize, prefs_json: &json::JsonValue)
+{
+    let path = |extension: &str| format!("{}/{:020}.{}", snapshots_dir, n, extension);
+    let (rule, topology) = (automata.rule, automata.topology);
+    let field = automata.field();
+    if prefs_json["snapshot_png"].as_bool().unwrap_or(true) {
+        png::snapshot_png(field, rule.states, &path("png"));
+    }
+    if prefs_json["snapshot_rle"].as_bool().unwrap_or(false) {
+        pattern::snapshot_rle(field, &rule, &path("rle")).unwrap();
+    }
+    if prefs_json["snapshot_checkpoint"].as_bool().unwrap_or(false) {
+        checkpoint::save(
+            &path("ckpt"),
+            field,
+            &rule,
+            topology,
+            n,
+            prefs_json["checkpoint_compress"].as_bool().unwrap_or(true),
+        ).unwrap();
+    }
 }
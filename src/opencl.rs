@@ -0,0 +1,505 @@
+extern crate opencl3 as cl;
+use opencl::cl::memory::ClMem;
+
+use automata::AutomataError;
+use automata::Field;
+use automata::Table;
+use automata::Topology;
+use backend::Backend;
+use backend::Blocks;
+use backend::ClKernels;
+use rule::Rule;
+use rule::Transition;
+
+
+/*
+Plays the rounds on an OpenCL GPU, using the "play_sliced" kernel in
+kernels.cl for outer totalistic rules, which counts the neighbours of 32
+cells at once, or else the lookup table: "play_blocked", which plays several
+rounds per launch, "play_tiled" for one round per launch, or "play" without
+a tile size (-> ClKernels),
+"play_generations" for Generations rules, or "play_ltl" for Larger-than-Life
+rules beyond radius 1.
+*/
+
+// the work groups of the "population" and "hash" kernels, and their sizes (powers of 2)
+const REDUCE_GROUPS: usize = 256;
+const REDUCE_LOCAL_SIZE: usize = 64;
+
+pub struct ClBackend {
+    w: usize,
+    w8: usize,
+    h: usize,
+    generations: bool,
+    ltl: bool,
+    sliced: bool,
+    // the work group size of "play_tiled", if it's used
+    tile: Option<(usize, usize)>,
+    // the rounds per launch of "play_blocked"; 1 for the other kernels
+    block_rounds: usize,
+    cl_context: cl::context::Context,
+    fields_swapped: bool,
+    clb_field0: cl::memory::Buffer<u8>,
+    clb_field1: cl::memory::Buffer<u8>,
+    #[allow(dead_code)] clb_table: cl::memory::Buffer<u8>,
+    cl_command_queue: cl::command_queue::CommandQueue,
+    clk_play: cl::kernel::Kernel,
+    clk_population: cl::kernel::Kernel,
+    clk_hash: cl::kernel::Kernel,
+    clb_partial: cl::memory::Buffer<u64>,
+    clk_density: cl::kernel::Kernel,
+    // for "density", made as large as needed
+    clb_density: Option<(cl::memory::Buffer<u8>, usize)>,
+}
+
+impl ClBackend
+{
+    pub fn new (
+            gpu_i: usize,
+            kernels: ClKernels,
+            rule: &Rule,
+            topology: Topology,
+            w: usize,
+            h: usize,
+    ) -> Result<ClBackend, Box<dyn std::error::Error>>
+    {
+        let w8 = ((w as f64) / 8_f64).ceil() as usize;
+        let table = Table::from_rule(rule);
+        let generations = rule.states > 2;
+        let ltl = rule.radius() > 1;
+        let sliced = kernels.sliced && rule.outer_totalistic().is_some();
+        let tile = if generations || ltl || sliced { None } else { kernels.tile };
+        let block_rounds = if tile.is_some() { kernels.block_rounds } else { 1 };
+        // the halo around the tile of "play_blocked" (or "play_tiled"), in bytes and rows
+        let halo = (block_rounds.div_ceil(8), block_rounds);
+        // for Generations rules, one byte per cell
+        let field_size = if generations { h*w } else { h*w8 };
+        let mut devices = Vec::<cl::types::cl_device_id>::new();
+        for platform in cl::platform::get_platforms().unwrap() {
+            for device in platform.get_devices(cl::device::CL_DEVICE_TYPE_GPU).unwrap() {
+                devices.push(device);
+            }
+        }
+        if gpu_i >= devices.len() {
+            return Err(Box::new(AutomataError::new(
+                "Cannot find a suitable OpenCL device for gpu_i."
+            )));
+        }
+        let device = cl::device::Device::new(devices[gpu_i]);
+        if let Some((tile_w, tile_h)) = tile {
+            let max_sizes = device.max_work_item_sizes().unwrap();
+            if tile_w*tile_h > device.max_work_group_size().unwrap()
+                    || tile_w > max_sizes[0] || tile_h > max_sizes[1] {
+                return Err(Box::new(AutomataError::new(&format!(
+                    "The tile ({}×{}) is too large for the OpenCL device (work groups of up to {} and {:?}).",
+                    tile_w, tile_h, device.max_work_group_size().unwrap(), max_sizes
+                ))));
+            }
+            // "play_blocked" needs two tiles
+            let local_size = (tile_w + 2*halo.0)*(tile_h + 2*halo.1) * if block_rounds > 1 { 2 } else { 1 };
+            if local_size as u64 > device.local_mem_size().unwrap() {
+                return Err(Box::new(AutomataError::new(&format!(
+                    "The tile ({}×{}) with {} rounds per launch needs {} bytes of local memory; the OpenCL device has {}.",
+                    tile_w, tile_h, block_rounds, local_size, device.local_mem_size().unwrap()
+                ))));
+            }
+        }
+        let cl_context = cl::context::Context::from_device(&device).unwrap();
+        let cl_command_queue = cl::command_queue::CommandQueue::create_with_properties(
+            &cl_context,
+            device.id(),
+            0, //properties
+            0 //queue_size
+        ).unwrap();
+        //bake in lookup values
+        let mut program_source = String::from("__constant uchar TABLE[] = ");
+        program_source += &table.as_cl_arr();
+        program_source.push_str(";\n");
+        program_source.push_str(&format!("#define ZEROES_B 0x{:X}\n", table.zeroes_b));
+        program_source.push_str(&format!("#define ONES_B 0x{:X}\n", table.ones_b));
+        program_source.push_str(&format!("#define TORUS {}\n", (topology == Topology::Torus) as u8));
+        program_source.push_str("__constant uchar NEXT[] = ");
+        program_source += &next_as_cl_arr(rule);
+        program_source.push_str(";\n");
+        program_source.push_str(&format!("#define STATES {}\n", rule.states));
+        program_source += &ltl_as_cl_defines(rule);
+        program_source.push_str(&format!("#define BLOCK_ROUNDS {}\n", block_rounds));
+        program_source += &sliced_as_cl_defines(rule, sliced);
+        program_source.push_str("\n\n");
+        program_source.push_str(include_str!("kernels.cl"));
+        let program = cl::program::Program::create_and_build_from_source(
+            &cl_context,
+            &program_source,
+            "" //options
+        ).unwrap();
+        let kernel_name = if generations {
+            "play_generations"
+        } else if ltl {
+            "play_ltl"
+        } else if sliced {
+            "play_sliced"
+        } else if block_rounds > 1 {
+            "play_blocked"
+        } else if tile.is_some() {
+            "play_tiled"
+        } else {
+            "play"
+        };
+        let clk_play = cl::kernel::Kernel::new(
+            cl::kernel::create_kernel(
+                program.get(),
+                &std::ffi::CString::new(kernel_name).unwrap()
+            ).unwrap()
+        );
+        let clk_population = cl::kernel::Kernel::new(
+            cl::kernel::create_kernel(
+                program.get(),
+                &std::ffi::CString::new("population").unwrap()
+            ).unwrap()
+        );
+        let clk_hash = cl::kernel::Kernel::new(
+            cl::kernel::create_kernel(
+                program.get(),
+                &std::ffi::CString::new("hash").unwrap()
+            ).unwrap()
+        );
+        let clk_density = cl::kernel::Kernel::new(
+            cl::kernel::create_kernel(
+                program.get(),
+                &std::ffi::CString::new("density").unwrap()
+            ).unwrap()
+        );
+        let clb_field0 = cl::memory::Buffer::create(
+            &cl_context,
+            cl::memory::CL_MEM_READ_WRITE,
+            field_size,
+            std::ptr::null_mut()
+        ).unwrap();
+        let clb_field1 = cl::memory::Buffer::create(
+            &cl_context,
+            cl::memory::CL_MEM_READ_WRITE,
+            field_size,
+            std::ptr::null_mut()
+        ).unwrap();
+        let clb_partial = cl::memory::Buffer::create(
+            &cl_context,
+            cl::memory::CL_MEM_READ_WRITE,
+            REDUCE_GROUPS,
+            std::ptr::null_mut()
+        ).unwrap();
+        let mut clb_table = cl::memory::Buffer::create(
+            &cl_context,
+            cl::memory::CL_MEM_READ_WRITE,
+            table.values.len(),
+            std::ptr::null_mut()
+        ).unwrap();
+        cl_command_queue.enqueue_write_buffer(
+            &mut clb_table,
+            1, //blocking_write
+            0, //offset
+            &table.values,
+            &[] //event_wait_list
+        ).unwrap();
+        clk_play.set_arg(0, &(w as u32)).unwrap();
+        clk_play.set_arg(1, &(h as u32)).unwrap();
+        // 2 (source) set in loop
+        // 3 (target) set in loop
+        if let Some((tile_w, tile_h)) = tile {
+            // the tile with its halo
+            let tile_size = (tile_w + 2*halo.0)*(tile_h + 2*halo.1);
+            if block_rounds > 1 {
+                // 2 (k) set in play
+                // 3 (source) and 4 (target) set in loop
+                clk_play.set_arg_local_buffer(5, tile_size).unwrap();
+                clk_play.set_arg_local_buffer(6, tile_size).unwrap();
+            } else {
+                clk_play.set_arg_local_buffer(4, tile_size).unwrap();
+            }
+        } else if !generations && !ltl && !sliced {
+            clk_play.set_arg_local_buffer(4, 3*w8).unwrap();
+        }
+        for clk in [&clk_population, &clk_hash] {
+            // 0 (field) set in reduce
+            clk.set_arg(1, &(field_size as u64)).unwrap();
+            clk.set_arg(2, &clb_partial.get()).unwrap();
+            clk.set_arg_local_buffer(3, REDUCE_LOCAL_SIZE*8).unwrap();
+        }
+        clk_density.set_arg(0, &(w as u32)).unwrap();
+        clk_density.set_arg(1, &(h as u32)).unwrap();
+        // the others set in density
+        Ok(ClBackend {
+            w,
+            w8,
+            h,
+            generations,
+            ltl,
+            sliced,
+            tile,
+            block_rounds,
+            cl_context,
+            fields_swapped: false,
+            clb_field0,
+            clb_field1,
+            clb_table,
+            cl_command_queue,
+            clk_play,
+            clk_population,
+            clk_hash,
+            clb_partial,
+            clk_density,
+            clb_density: None,
+        })
+    }
+
+    // the buffer holding the latest generation
+    fn current (&self) -> &cl::memory::Buffer<u8> {
+        if self.fields_swapped {
+            &self.clb_field1
+        } else {
+            &self.clb_field0
+        }
+    }
+
+    // runs "population" or "hash" on the latest generation, and adds up the partial sums
+    fn reduce (&self, clk: &cl::kernel::Kernel) -> u64
+    {
+        clk.set_arg(0, &self.current().get()).unwrap();
+        self.cl_command_queue.enqueue_nd_range_kernel(
+            clk.get(),
+            1, //work_dim
+            [0].as_ptr(), //global_work_offsets
+            [REDUCE_GROUPS * REDUCE_LOCAL_SIZE].as_ptr(), //global_work_sizes
+            [REDUCE_LOCAL_SIZE].as_ptr(), //local_work_sizes
+            &[] //event_wait_list
+        ).unwrap();
+        // only the partial sums are read back
+        let mut partial = vec![0u64; REDUCE_GROUPS];
+        self.cl_command_queue.enqueue_read_buffer(
+            &self.clb_partial,
+            1, //blocking_read
+            0, //offset
+            &mut partial,
+            &[] //event_wait_list
+        ).unwrap();
+        // wrapping, for the hash
+        partial.iter().fold(0, |sum, &p| sum.wrapping_add(p))
+    }
+}
+
+impl Backend for ClBackend
+{
+    fn upload (&mut self, field: &Field)
+    {
+        let cl_command_queue = &self.cl_command_queue;
+        let clb_source = if self.fields_swapped {
+            &mut self.clb_field1
+        } else {
+            &mut self.clb_field0
+        };
+        cl_command_queue.enqueue_write_buffer(
+            clb_source,
+            1, //blocking_write
+            0, //offset
+            if self.generations { field.states() } else { field.data() },
+            &[] //event_wait_list
+        ).unwrap();
+    }
+
+    fn play (&mut self, n_rounds: usize)
+    {
+        let cl_command_queue = &self.cl_command_queue;
+        let clk_play = &self.clk_play;
+        let mut rounds_left = n_rounds;
+        while rounds_left > 0 {
+            let (clb_source, clb_target);
+            if self.fields_swapped {
+                clb_source = &self.clb_field1;
+                clb_target = &self.clb_field0;
+            } else {
+                clb_source = &self.clb_field0;
+                clb_target = &self.clb_field1;
+            }
+            // "play_blocked" plays up to block_rounds rounds at once
+            let k = rounds_left.min(self.block_rounds);
+            if self.block_rounds > 1 {
+                clk_play.set_arg(2, &(k as u32)).unwrap();
+                clk_play.set_arg(3, &clb_source.get()).unwrap();
+                clk_play.set_arg(4, &clb_target.get()).unwrap();
+            } else {
+                clk_play.set_arg(2, &clb_source.get()).unwrap();
+                clk_play.set_arg(3, &clb_target.get()).unwrap();
+            }
+            // go, using OpenCL
+            if let Some((tile_w, tile_h)) = self.tile {
+                // every byte, in whole tiles
+                cl_command_queue.enqueue_nd_range_kernel(
+                    clk_play.get(),
+                    2, //work_dim; for every (x8, y)
+                    [0, 0].as_ptr(), //global_work_offsets
+                    [self.w8.div_ceil(tile_w)*tile_w, self.h.div_ceil(tile_h)*tile_h].as_ptr(), //global_work_sizes
+                    [tile_w, tile_h].as_ptr(), //local_work_sizes
+                    &[] //event_wait_list
+                ).unwrap();
+            } else if self.sliced {
+                // every 32 cells of a row
+                cl_command_queue.enqueue_nd_range_kernel(
+                    clk_play.get(),
+                    2, //work_dim; for every (x32, y)
+                    [0, 0].as_ptr(), //global_work_offsets
+                    [self.w.div_ceil(32), self.h].as_ptr(), //global_work_sizes
+                    std::ptr::null(), //local_work_sizes: up to OpenCL
+                    &[] //event_wait_list
+                ).unwrap();
+            } else if self.generations || self.ltl {
+                // every cell, or every 8×1 slice for "play_ltl"
+                let x_size = if self.generations { self.w } else { self.w8 };
+                cl_command_queue.enqueue_nd_range_kernel(
+                    clk_play.get(),
+                    2, //work_dim; for every (x, y)
+                    [0, 0].as_ptr(), //global_work_offsets
+                    [x_size, self.h].as_ptr(), //global_work_sizes
+                    std::ptr::null(), //local_work_sizes: up to OpenCL
+                    &[] //event_wait_list
+                ).unwrap();
+            } else {
+                cl_command_queue.enqueue_nd_range_kernel(
+                    clk_play.get(),
+                    1, //work_dim; for: y=0, y=1, y=2, ... y=h-1
+                    [0].as_ptr(), //global_work_offsets
+                    [self.h].as_ptr(), //global_work_sizes
+                    [1].as_ptr(), //local_work_sizes
+                    &[] //event_wait_list
+                ).unwrap();
+            }
+            // clean up
+            cl_command_queue.finish().unwrap();
+            self.fields_swapped = !self.fields_swapped;
+            rounds_left -= k;
+        }
+    }
+
+    fn download (&mut self, field: &mut Field)
+    {
+        if self.generations {
+            self.cl_command_queue.enqueue_read_buffer(
+                self.current(),
+                1, //blocking_read
+                0, //offset
+                field.states_mut(),
+                &[] //event_wait_list
+            ).unwrap();
+            field.update_data();
+            return;
+        }
+        self.cl_command_queue.enqueue_read_buffer(
+            self.current(),
+            1, //blocking_read
+            0, //offset
+            field.data_mut(),
+            &[] //event_wait_list
+        ).unwrap();
+    }
+
+    fn population (&mut self) -> u64
+    {
+        self.reduce(&self.clk_population)
+    }
+
+    fn hash (&mut self) -> u64
+    {
+        self.reduce(&self.clk_hash)
+    }
+
+    fn density (&mut self, blocks: &Blocks, out: &mut [u8])
+    {
+        let size = blocks.nx * blocks.ny;
+        if self.clb_density.as_ref().is_none_or(|&(_, density_size)| density_size < size) {
+            let clb_density = cl::memory::Buffer::create(
+                &self.cl_context,
+                cl::memory::CL_MEM_WRITE_ONLY,
+                size,
+                std::ptr::null_mut()
+            ).unwrap();
+            self.clb_density = Some((clb_density, size));
+        }
+        let clb_density = &self.clb_density.as_ref().unwrap().0;
+        let clk_density = &self.clk_density;
+        clk_density.set_arg(2, &self.current().get()).unwrap();
+        // in single precision: an edge right at a cell may end up on the other side of it
+        clk_density.set_arg(3, &(blocks.x0 as f32)).unwrap();
+        clk_density.set_arg(4, &(blocks.y0 as f32)).unwrap();
+        clk_density.set_arg(5, &(blocks.block_w as f32)).unwrap();
+        clk_density.set_arg(6, &(blocks.block_h as f32)).unwrap();
+        clk_density.set_arg(7, &(blocks.shear as f32)).unwrap();
+        clk_density.set_arg(8, &clb_density.get()).unwrap();
+        self.cl_command_queue.enqueue_nd_range_kernel(
+            clk_density.get(),
+            2, //work_dim; for every block
+            [0, 0].as_ptr(), //global_work_offsets
+            [blocks.nx, blocks.ny].as_ptr(), //global_work_sizes
+            std::ptr::null(), //local_work_sizes: up to OpenCL
+            &[] //event_wait_list
+        ).unwrap();
+        // only one byte per block is read back
+        self.cl_command_queue.enqueue_read_buffer(
+            clb_density,
+            1, //blocking_read
+            0, //offset
+            &mut out[..size],
+            &[] //event_wait_list
+        ).unwrap();
+    }
+}
+
+/*
+The next states of all 512 3×3 neighbourhoods (see rule.rs), as bits for the
+"NEXT" array in kernels.cl.
+*/
+fn next_as_cl_arr (rule: &Rule) -> String {
+    let mut bytes = [0u8; 512/8];
+    for env in 0..512 {
+        if rule.next(env) {
+            bytes[env as usize / 8] |= 1 << (env%8);
+        }
+    }
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:X}", b)).collect();
+    format!("{{{}}}", bytes.join(", "))
+}
+
+/*
+The parameters of a Larger-than-Life rule for "play_ltl" in kernels.cl;
+LTL_RADIUS is 0 (and play_ltl left out) for all other rules.
+*/
+fn ltl_as_cl_defines (rule: &Rule) -> String {
+    let (radius, middle, survives, borns, neighbourhood) = match rule.transition {
+        Transition::LargerThanLife { radius, middle, survives, borns, neighbourhood }
+            if radius > 1 => (radius, middle, survives, borns, neighbourhood),
+        _ => return String::from("#define LTL_RADIUS 0\n"),
+    };
+    let extents: Vec<String> = (0..=2*radius)
+        .map(|i| neighbourhood.extent(radius, i.abs_diff(radius)).to_string())
+        .collect();
+    format!(
+        "#define LTL_RADIUS {}\n#define LTL_MIDDLE {}\n\
+        #define S_MIN {}\n#define S_MAX {}\n#define B_MIN {}\n#define B_MAX {}\n\
+        __constant ushort EXTENT[] = {{{}}};\n",
+        radius, middle as u8,
+        survives.0, survives.1, borns.0, borns.1,
+        extents.join(", ")
+    )
+}
+
+/*
+The birth and survival counts for "play_sliced" in kernels.cl; SLICED is 0
+(and play_sliced left out) if the rule isn't outer totalistic, or if the
+lookup table is wanted anyway.
+*/
+fn sliced_as_cl_defines (rule: &Rule, sliced: bool) -> String {
+    match rule.outer_totalistic() {
+        Some((borns, survives)) if sliced => format!(
+            "#define SLICED 1\n#define SLICED_BORNS 0x{:X}\n#define SLICED_SURVIVES 0x{:X}\n",
+            borns, survives
+        ),
+        _ => String::from("#define SLICED 0\n"),
+    }
+}
@@ -0,0 +1,847 @@
+use automata::Field;
+use rule::Rule;
+use rule::RuleError;
+
+
+/*
+A "pattern" is a bunch of cells from a pattern file, as found on LifeWiki,
+Catagolue or in Golly's collection, which can be placed into a field.
+RLE ("Run Length Encoded") looks like this:
+    #N Glider
+    x = 3, y = 3, rule = B3/S23
+    bob$2bo$3o!
+"b" (or ".") is a dead cell and "o" a live one, with an optional count in
+front; "$" ends a row and "!" the pattern. For Generations rules, the states
+1 to 24 are "A" to "X", and the states from 25 on have a prefix "p" to "y",
+e.g. "pA" for 25.
+Plaintext (.cells) has one line per row, "." for dead and "O" for live cells,
+and comment lines starting with "!".
+Macrocell (.mc, Golly's quadtree format) lists the distinct nodes of the
+tree, each line numbered from 1, with the root last:
+    [M2] (golly 4.2)
+    #R B3/S23
+    .*$..*$***$      an 8×8 leaf, "*" being a live cell and "$" a row end
+    4 0 1 0 0        a 16×16 node with its NW, NE, SW and SE children (0: empty)
+Multi-state macrocell files have 2×2 nodes with states ("1 0 2 0 1") instead
+of 8×8 leaves.
+*/
+
+pub struct Pattern {
+    pub w: usize,
+    pub h: usize,
+    // (x, y, count, state): runs of count cells from (x, y) on that aren't dead
+    pub runs: Vec<(usize, usize, usize, u8)>,
+    // the rule that the file suggests, if any
+    pub rule: Option<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    // "x = ..., y = ..." is missing or malformed
+    InvalidHeader { line: usize },
+    InvalidRule { line: usize, err: RuleError },
+    UnexpectedChar { c: char, line: usize },
+    // a state beyond 255
+    InvalidState { line: usize },
+    // more cells than fit into memory (see fits_into_memory)
+    TooLarge { line: usize },
+    // a macrocell node with children that don't exist or don't fit
+    InvalidNode { line: usize },
+    // no cells at all (w or h 0), or no live cells in a macrocell file
+    Empty,
+    // the extension is none of ".rle", ".mc" and ".cells"
+    UnknownFormat,
+}
+
+// where Pattern::to_field puts the pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Centred,
+    // the top left corner of the pattern
+    At(isize, isize),
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PatternError::InvalidHeader { line } =>
+                write!(f, "Invalid header in line {} of the pattern (expected \"x = ..., y = ...\").", line),
+            PatternError::InvalidRule { line, ref err } =>
+                write!(f, "Invalid rule in line {} of the pattern: {}", line, err),
+            PatternError::UnexpectedChar { c, line } =>
+                write!(f, "Unexpected character '{}' in line {} of the pattern.", c, line),
+            PatternError::InvalidState { line } =>
+                write!(f, "Invalid state in line {} of the pattern (expected up to 255).", line),
+            PatternError::TooLarge { line } =>
+                write!(f, "The pattern is too large (line {}).", line),
+            PatternError::InvalidNode { line } =>
+                write!(f, "Invalid macrocell node in line {} of the pattern.", line),
+            PatternError::Empty =>
+                write!(f, "The pattern is empty."),
+            PatternError::UnknownFormat =>
+                write!(f, "Unknown pattern format (expected \".rle\", \".mc\" or \".cells\")."),
+        }
+    }
+}
+impl std::error::Error for PatternError {}
+
+// longest line in written RLE files, like Golly does
+const RLE_LINE_LEN: usize = 70;
+// the most bytes of a pattern's field, 8 GiB; more wouldn't fit into memory anyway
+const MAX_FIELD_BYTES: u128 = 1 << 33;
+// Golly doesn't go beyond this either; the coordinates fit into an i64.
+const MAX_MC_LEVEL: usize = 62;
+// the most live cells expanded from a macrocell file, 2 GiB as runs
+const MAX_MC_CELLS: usize = 1 << 26;
+
+// a node in a macrocell file
+enum McNode {
+    // 8 rows of 8 cells, LSB first, like a Field
+    Leaf([u8; 8]),
+    // the states of a 2×2 node: NW, NE, SW, SE
+    States([u8; 4]),
+    // a 2^level×2^level node; children as line indices (0: empty)
+    Node { level: usize, children: [usize; 4] },
+}
+
+// (x0, y0, x1, y1), inclusive
+type McBbox = (i64, i64, i64, i64);
+
+// the state of expanding a macrocell quadtree into runs
+struct McExpansion<'a> {
+    nodes: &'a [Option<McNode>],
+    bboxes: &'a [Option<McBbox>],
+    // the cells to expand (x0, y0, x1, y1), inclusive, relative to the root
+    window: McBbox,
+    // the top left corner of the pattern, relative to the root
+    origin: (i64, i64),
+    runs: Vec<(usize, usize, usize, u8)>,
+    cells: usize,
+}
+
+impl<'a> McExpansion<'a>
+{
+    // Expands the node at (x, y), skipping the quadrants outside the window; false once there are too many cells.
+    fn expand (&mut self, node_i: usize, x: i64, y: i64) -> bool
+    {
+        let (wx0, wy0, wx1, wy1) = self.window;
+        match self.bboxes[node_i] {
+            Some((x0, y0, x1, y1)) if x + x1 >= wx0 && x + x0 <= wx1 && y + y1 >= wy0 && y + y0 <= wy1 => {},
+            _ => return true,
+        }
+        match self.nodes[node_i] {
+            None => true,
+            Some(McNode::Leaf(rows)) => {
+                for (dy, row) in rows.iter().enumerate() {
+                    for dx in 0..8 {
+                        if (row >> dx) & 1 != 0 && !self.push(x + dx, y + dy as i64, 1) {
+                            return false;
+                        }
+                    }
+                }
+                true
+            },
+            Some(McNode::States(states)) => {
+                for (i, &state) in states.iter().enumerate() {
+                    if state != 0 && !self.push(x + (i%2) as i64, y + (i/2) as i64, state) {
+                        return false;
+                    }
+                }
+                true
+            },
+            Some(McNode::Node { level, children }) => {
+                let half = 1_i64 << (level - 1);
+                children.iter().enumerate().all(|(i, &child)| {
+                    let (dx, dy) = ((i%2) as i64 * half, (i/2) as i64 * half);
+                    self.expand(child, x + dx, y + dy)
+                })
+            },
+        }
+    }
+
+    fn push (&mut self, x: i64, y: i64, state: u8) -> bool
+    {
+        let (wx0, wy0, wx1, wy1) = self.window;
+        if x < wx0 || x > wx1 || y < wy0 || y > wy1 {
+            return true;
+        }
+        self.cells += 1;
+        push_run(&mut self.runs, (x - self.origin.0) as usize, (y - self.origin.1) as usize, 1, state);
+        self.cells <= MAX_MC_CELLS
+    }
+}
+
+impl Pattern
+{
+    /*
+    Loads a pattern file; the format is chosen by the extension
+    (".rle", ".mc" or ".cells").
+    */
+    pub fn load (path: &str) -> Result<Pattern, Box<dyn std::error::Error>>
+    {
+        let from_text = {
+            let path = path.to_ascii_lowercase();
+            if path.ends_with(".rle") {
+                Pattern::from_rle
+            } else if path.ends_with(".mc") {
+                Pattern::from_macrocell
+            } else if path.ends_with(".cells") {
+                Pattern::from_cells
+            } else {
+                return Err(Box::new(PatternError::UnknownFormat));
+            }
+        };
+        let text = std::fs::read_to_string(path)?;
+        Ok(from_text(&text)?)
+    }
+
+    /*
+    Like load, but for a w×h field: macrocell files are only expanded
+    as far as they end up in it (see from_macrocell_within).
+    */
+    pub fn load_within (path: &str, w: usize, h: usize, placement: Placement) -> Result<Pattern, Box<dyn std::error::Error>>
+    {
+        if !path.to_ascii_lowercase().ends_with(".mc") {
+            return Pattern::load(path);
+        }
+        let text = std::fs::read_to_string(path)?;
+        Ok(Pattern::from_macrocell_within(&text, w, h, placement)?)
+    }
+
+    pub fn from_rle (text: &str) -> Result<Pattern, PatternError>
+    {
+        let mut header: Option<(usize, usize)> = None;
+        let mut rule: Option<Rule> = None;
+        let mut runs = Vec::new();
+        let (mut x, mut y) = (0_usize, 0_usize);
+        let (mut w, mut h) = (0_usize, 0_usize);
+        let mut run: Option<usize> = None;
+        // a "p" to "y" in front of a state letter
+        let mut prefix: Option<u8> = None;
+        // whether the field needs a byte per cell for the states
+        let mut multi_state = false;
+        'lines: for (line_i, line) in text.lines().enumerate() {
+            let line_nr = line_i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if header.is_none() {
+                let (size, line_rule) = Pattern::parse_rle_header(line, line_nr)?;
+                multi_state = line_rule.is_some_and(|rule| rule.states > 2);
+                if !fits_into_memory(size.0 as u128, size.1 as u128, multi_state) {
+                    return Err(PatternError::TooLarge { line: line_nr });
+                }
+                header = Some(size);
+                rule = line_rule;
+                continue;
+            }
+            for c in line.chars() {
+                match c {
+                    '0' ..= '9' => {
+                        let digit = c as usize - '0' as usize;
+                        run = Some(run.unwrap_or(0).checked_mul(10)
+                            .and_then(|run| run.checked_add(digit))
+                            .ok_or(PatternError::TooLarge { line: line_nr })?);
+                        continue;
+                    },
+                    'p' ..= 'y' if prefix.is_none() => {
+                        prefix = Some(c as u8 - b'p' + 1);
+                        continue;
+                    },
+                    c if c.is_whitespace() => continue,
+                    _ => {},
+                }
+                let count = run.take().unwrap_or(1);
+                let state = match c {
+                    'b' | '.' if prefix.is_none() => 0,
+                    'o' if prefix.is_none() => 1,
+                    'A' ..= 'X' => {
+                        let state = prefix.take().unwrap_or(0) as usize * 24 + (c as usize - 'A' as usize) + 1;
+                        if state > 255 {
+                            return Err(PatternError::InvalidState { line: line_nr });
+                        }
+                        state as u8
+                    },
+                    '$' if prefix.is_none() => {
+                        y = y.checked_add(count).ok_or(PatternError::TooLarge { line: line_nr })?;
+                        x = 0;
+                        continue;
+                    },
+                    '!' if prefix.is_none() => break 'lines,
+                    // a prefix must be followed by a state letter
+                    _ => return Err(PatternError::UnexpectedChar {
+                        c: prefix.map_or(c, |prefix| (b'p' + prefix - 1) as char),
+                        line: line_nr,
+                    }),
+                };
+                if state != 0 && count > 0 {
+                    // checked before the run is stored, so that the field fits, too
+                    multi_state |= state > 1;
+                    let (header_w, header_h) = header.unwrap_or((0, 0));
+                    let run_w = (x as u128 + count as u128).max(w.max(header_w) as u128);
+                    let run_h = (y as u128 + 1).max(h.max(header_h) as u128);
+                    if !fits_into_memory(run_w, run_h, multi_state) {
+                        return Err(PatternError::TooLarge { line: line_nr });
+                    }
+                    push_run(&mut runs, x, y, count, state);
+                    w = w.max(x + count);
+                    h = h.max(y + 1);
+                }
+                x = x.saturating_add(count);
+            }
+        }
+        let (header_w, header_h) = header.ok_or(PatternError::InvalidHeader { line: 1 })?;
+        if w.max(header_w) == 0 || h.max(header_h) == 0 {
+            return Err(PatternError::Empty);
+        }
+        Ok(Pattern {
+            // cells beyond the header's size are tolerated, like in Golly
+            w: w.max(header_w),
+            h: h.max(header_h),
+            runs,
+            rule,
+        })
+    }
+
+    // "x = 3, y = 3, rule = B3/S23"
+    fn parse_rle_header (line: &str, line_nr: usize) -> Result<((usize, usize), Option<Rule>), PatternError>
+    {
+        let (mut w, mut h, mut rule) = (None, None, None);
+        // the rule comes last, and may contain commas (e.g. Golly's ":T64,64")
+        let (size, rule_item) = match line.find("rule") {
+            Some(rule_i) => (&line[..rule_i], Some(&line[rule_i..])),
+            None => (line, None),
+        };
+        for item in size.split(',').filter(|item| !item.trim().is_empty()) {
+            let (key, value) = item.split_once('=')
+                .ok_or(PatternError::InvalidHeader { line: line_nr })?;
+            let value = value.trim();
+            match key.trim() {
+                "x" => w = value.parse::<usize>().ok(),
+                "y" => h = value.parse::<usize>().ok(),
+                _ => return Err(PatternError::InvalidHeader { line: line_nr }),
+            }
+        }
+        if let Some(rule_item) = rule_item {
+            let (_, value) = rule_item.split_once('=')
+                .ok_or(PatternError::InvalidHeader { line: line_nr })?;
+            // Golly's topology suffixes are ignored here; see "topology" in seed.json
+            let rulestring = value.split(':').next().unwrap();
+            rule = Some(
+                Rule::parse(rulestring)
+                    .map_err(|err| PatternError::InvalidRule { line: line_nr, err })?
+            );
+        }
+        match (w, h) {
+            (Some(w), Some(h)) => Ok(((w, h), rule)),
+            _ => Err(PatternError::InvalidHeader { line: line_nr }),
+        }
+    }
+
+    pub fn from_cells (text: &str) -> Result<Pattern, PatternError>
+    {
+        let mut runs = Vec::new();
+        let (mut w, mut h) = (0, 0);
+        let lines = text.lines().enumerate().filter(|(_, line)| !line.starts_with('!'));
+        for (y, (line_i, line)) in lines.enumerate() {
+            for (x, c) in line.trim_end().chars().enumerate() {
+                match c {
+                    '.' => {},
+                    'O' | '*' => {
+                        push_run(&mut runs, x, y, 1, 1);
+                        w = w.max(x + 1);
+                        h = y + 1;
+                    },
+                    _ => return Err(PatternError::UnexpectedChar { c, line: line_i + 1 }),
+                }
+            }
+        }
+        if runs.is_empty() {
+            return Err(PatternError::Empty);
+        }
+        Ok(Pattern {
+            w,
+            h,
+            runs,
+            rule: None,
+        })
+    }
+
+    /*
+    The pattern is cut down to its live cells: w and h are those of the
+    bounding box, and the cells are relative to its top left corner.
+    */
+    pub fn from_macrocell (text: &str) -> Result<Pattern, PatternError>
+    {
+        Pattern::parse_macrocell(text, None)
+    }
+
+    /*
+    Like from_macrocell, but only the cells that end up in a w×h field
+    (see to_field) are expanded; quadtrees may hold far more cells than
+    would fit into memory.
+    */
+    pub fn from_macrocell_within (text: &str, w: usize, h: usize, placement: Placement) -> Result<Pattern, PatternError>
+    {
+        Pattern::parse_macrocell(text, Some((w, h, placement)))
+    }
+
+    fn parse_macrocell (text: &str, field: Option<(usize, usize, Placement)>) -> Result<Pattern, PatternError>
+    {
+        let mut rule: Option<Rule> = None;
+        // node 0 is the empty node; the others are numbered as in the file
+        let mut nodes: Vec<Option<McNode>> = vec![None];
+        let mut root_line = 0;
+        for (line_i, line) in text.lines().enumerate() {
+            let line_nr = line_i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            if let Some(rulestring) = line.strip_prefix("#R") {
+                rule = Some(
+                    Rule::parse(rulestring.trim().split(':').next().unwrap())
+                        .map_err(|err| PatternError::InvalidRule { line: line_nr, err })?
+                );
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let node = if line.starts_with(['.', '*', '$']) {
+                Pattern::parse_mc_leaf(line, line_nr)?
+            } else {
+                Pattern::parse_mc_node(line, line_nr, &nodes)?
+            };
+            nodes.push(Some(node));
+            root_line = line_nr;
+        }
+        // the bounding box of the root's live cells, with its top left corner at (0, 0)
+        let bboxes = Pattern::mc_bboxes(&nodes);
+        let (x_min, y_min, x_max, y_max) = bboxes[nodes.len() - 1].ok_or(PatternError::Empty)?;
+        let (w, h) = ((x_max - x_min + 1) as usize, (y_max - y_min + 1) as usize);
+        // the part of the bounding box to expand
+        let window = match field {
+            None => {
+                let multi_state = rule.is_some_and(|rule| rule.states > 2) || nodes.iter().any(|node| {
+                    matches!(node, Some(McNode::States(states)) if states.iter().any(|&state| state > 1))
+                });
+                if !fits_into_memory(w as u128, h as u128, multi_state) {
+                    return Err(PatternError::TooLarge { line: root_line });
+                }
+                (x_min, y_min, x_max, y_max)
+            },
+            Some((field_w, field_h, placement)) => {
+                let (x, y) = match placement {
+                    Placement::Centred => (
+                        (field_w as i64 - w as i64) / 2,
+                        (field_h as i64 - h as i64) / 2,
+                    ),
+                    Placement::At(x, y) => (x as i64, y as i64),
+                };
+                (x_min - x, y_min - y, x_min - x + field_w as i64 - 1, y_min - y + field_h as i64 - 1)
+            },
+        };
+        let mut expansion = McExpansion {
+            nodes: &nodes,
+            bboxes: &bboxes,
+            window,
+            origin: (x_min, y_min),
+            runs: Vec::new(),
+            cells: 0,
+        };
+        if !expansion.expand(nodes.len() - 1, 0, 0) {
+            return Err(PatternError::TooLarge { line: root_line });
+        }
+        // row by row instead of the quadtree's order, so that runs across nodes join up
+        expansion.runs.sort_unstable_by_key(|&(x, y, _, _)| (y, x));
+        let mut runs = Vec::new();
+        for (x, y, count, state) in expansion.runs {
+            push_run(&mut runs, x, y, count, state);
+        }
+        Ok(Pattern {
+            w,
+            h,
+            runs,
+            rule,
+        })
+    }
+
+    // the bounding boxes of the nodes' live cells, relative to them; children come before their parents
+    fn mc_bboxes (nodes: &[Option<McNode>]) -> Vec<Option<McBbox>>
+    {
+        let mut bboxes: Vec<Option<McBbox>> = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let mut bbox: Option<McBbox> = None;
+            let mut include = |x0: i64, y0: i64, x1: i64, y1: i64| {
+                bbox = Some(match bbox {
+                    None => (x0, y0, x1, y1),
+                    Some(b) => (b.0.min(x0), b.1.min(y0), b.2.max(x1), b.3.max(y1)),
+                });
+            };
+            match *node {
+                None => {},
+                Some(McNode::Leaf(rows)) => {
+                    for (y, row) in rows.iter().enumerate() {
+                        if *row != 0 {
+                            let (x0, x1) = (row.trailing_zeros() as i64, 7 - row.leading_zeros() as i64);
+                            include(x0, y as i64, x1, y as i64);
+                        }
+                    }
+                },
+                Some(McNode::States(states)) => {
+                    for (i, &state) in states.iter().enumerate() {
+                        if state != 0 {
+                            let (x, y) = ((i%2) as i64, (i/2) as i64);
+                            include(x, y, x, y);
+                        }
+                    }
+                },
+                Some(McNode::Node { level, children }) => {
+                    let half = 1_i64 << (level - 1);
+                    for (i, &child) in children.iter().enumerate() {
+                        let (dx, dy) = ((i%2) as i64 * half, (i/2) as i64 * half);
+                        if let Some(b) = bboxes[child] {
+                            include(b.0 + dx, b.1 + dy, b.2 + dx, b.3 + dy);
+                        }
+                    }
+                },
+            }
+            bboxes.push(bbox);
+        }
+        bboxes
+    }
+
+    // ".*$..*$***$"
+    fn parse_mc_leaf (line: &str, line_nr: usize) -> Result<McNode, PatternError>
+    {
+        let mut rows = [0u8; 8];
+        let (mut x, mut y) = (0, 0);
+        for c in line.chars() {
+            match c {
+                '.' | '*' if x < 8 && y < 8 => {
+                    rows[y] |= ((c == '*') as u8) << x;
+                    x += 1;
+                },
+                '$' => {
+                    x = 0;
+                    y += 1;
+                },
+                _ => return Err(PatternError::UnexpectedChar { c, line: line_nr }),
+            }
+        }
+        Ok(McNode::Leaf(rows))
+    }
+
+    // "4 0 1 0 0", or "1 0 2 0 1" with states
+    fn parse_mc_node (line: &str, line_nr: usize, nodes: &[Option<McNode>]) -> Result<McNode, PatternError>
+    {
+        let numbers: Vec<usize> = line.split_whitespace()
+            .map(|number| number.parse::<usize>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| PatternError::InvalidNode { line: line_nr })?;
+        if numbers.len() != 5 || numbers[0] == 0 || numbers[0] > MAX_MC_LEVEL {
+            return Err(PatternError::InvalidNode { line: line_nr });
+        }
+        let level = numbers[0];
+        let mut children = [0; 4];
+        children.copy_from_slice(&numbers[1..]);
+        if level == 1 {
+            if children.iter().any(|&state| state > 255) {
+                return Err(PatternError::InvalidState { line: line_nr });
+            }
+            return Ok(McNode::States(children.map(|state| state as u8)));
+        }
+        // the children must be earlier nodes, one level below
+        for &child in &children {
+            let child_level = match nodes.get(child) {
+                Some(None) => continue, // empty
+                Some(Some(McNode::Leaf(_))) => 3,
+                Some(Some(McNode::States(_))) => 1,
+                Some(Some(McNode::Node { level, .. })) => *level,
+                None => return Err(PatternError::InvalidNode { line: line_nr }),
+            };
+            if child_level != level - 1 {
+                return Err(PatternError::InvalidNode { line: line_nr });
+            }
+        }
+        Ok(McNode::Node { level, children })
+    }
+
+    // all cells of the field, which may be huge, but RLE compresses dead space well
+    pub fn from_field (field: &Field, rule: Option<Rule>) -> Pattern
+    {
+        let mut runs = Vec::new();
+        for y in 0..field.h {
+            for x in 0..field.w {
+                let state = field.state(x, y);
+                if state != 0 {
+                    push_run(&mut runs, x, y, 1, state);
+                }
+            }
+        }
+        Pattern {
+            w: field.w,
+            h: field.h,
+            runs,
+            rule,
+        }
+    }
+
+    pub fn to_rle (&self) -> String
+    {
+        let mut rle = format!("x = {}, y = {}", self.w, self.h);
+        if let Some(rule) = self.rule {
+            rle += &format!(", rule = {}", rule);
+        }
+        rle.push('\n');
+        let multi_state = self.rule.is_some_and(|rule| rule.states > 2)
+            || self.runs.iter().any(|&(_, _, _, state)| state > 1);
+        let mut pattern_runs = self.runs.clone();
+        pattern_runs.sort_by_key(|&(x, y, _, _)| (y, x));
+        // the runs, as (count, tag)
+        let mut runs: Vec<(usize, String)> = Vec::new();
+        let mut push = |count: usize, tag: String| {
+            match runs.last_mut() {
+                Some(last) if last.1 == tag => last.0 += count,
+                _ => runs.push((count, tag)),
+            }
+        };
+        let (mut x, mut y) = (0, 0);
+        for &(cx, cy, count, state) in &pattern_runs {
+            if cy > y {
+                push(cy - y, String::from("$"));
+                y = cy;
+                x = 0;
+            }
+            if cx > x {
+                push(cx - x, String::from(if multi_state { "." } else { "b" }));
+            }
+            let tag = if !multi_state {
+                String::from("o")
+            } else if state <= 24 {
+                ((b'A' + state - 1) as char).to_string()
+            } else {
+                let (prefix, letter) = ((state - 1) / 24, (state - 1) % 24);
+                format!("{}{}", (b'p' + prefix - 1) as char, (b'A' + letter) as char)
+            };
+            push(count, tag);
+            x = cx + count;
+        }
+        push(1, String::from("!"));
+        // wrap the lines
+        let mut line_len = 0;
+        for (count, tag) in runs {
+            let run = if count > 1 { format!("{}{}", count, tag) } else { tag };
+            if line_len + run.len() > RLE_LINE_LEN {
+                rle.push('\n');
+                line_len = 0;
+            }
+            line_len += run.len();
+            rle += &run;
+        }
+        rle.push('\n');
+        rle
+    }
+
+    /*
+    Sets the cells in the field, with the pattern's top left corner at (x, y).
+    Cells that would lie beyond the edges of the field are left out.
+    */
+    pub fn place (&self, field: &mut Field, x: isize, y: isize)
+    {
+        for &(cx, cy, count, state) in &self.runs {
+            let fy = y + cy as isize;
+            if fy < 0 || fy >= field.h as isize {
+                continue;
+            }
+            // just the part of the run within the field
+            let fx0 = (x + cx as isize).max(0);
+            let fx1 = (x + cx as isize).saturating_add(count as isize).min(field.w as isize);
+            for fx in fx0..fx1 {
+                if state > 1 {
+                    field.set_state(fx as usize, fy as usize, state);
+                } else {
+                    field.set(fx as usize, fy as usize, true);
+                }
+            }
+        }
+    }
+
+    // A new field of w×h cells with the pattern in it.
+    pub fn to_field (&self, w: usize, h: usize, placement: Placement) -> Field
+    {
+        let mut field = Field::new(w, h);
+        let (x, y) = match placement {
+            Placement::Centred => (
+                (w as isize - self.w as isize) / 2,
+                (h as isize - self.h as isize) / 2,
+            ),
+            Placement::At(x, y) => (x, y),
+        };
+        self.place(&mut field, x, y);
+        field
+    }
+}
+
+/*
+Whether a field of w×h cells fits within MAX_FIELD_BYTES: one bit per cell,
+plus a byte per cell for the states of multi-state patterns (see
+Field::enable_states).
+*/
+fn fits_into_memory (w: u128, h: u128, multi_state: bool) -> bool
+{
+    let cells = w.saturating_mul(h);
+    let bytes = if multi_state { cells.saturating_add(cells / 8) } else { cells / 8 };
+    bytes <= MAX_FIELD_BYTES
+}
+
+// adds a run, or makes the last one longer if it goes on from there
+fn push_run (runs: &mut Vec<(usize, usize, usize, u8)>, x: usize, y: usize, count: usize, state: u8)
+{
+    match runs.last_mut() {
+        Some(last) if last.1 == y && last.3 == state && last.0 + last.2 == x => last.2 += count,
+        _ => runs.push((x, y, count, state)),
+    }
+}
+
+// Writes the field as an RLE file.
+pub fn snapshot_rle (field: &Field, rule: &Rule, path: &str) -> std::io::Result<()>
+{
+    std::fs::write(path, Pattern::from_field(field, Some(*rule)).to_rle())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLIDER: &str = "#N Glider\nx = 3, y = 3, rule = B3/S23\nbob$2bo$3o!\n";
+
+    #[test]
+    fn rle () {
+        let glider = Pattern::from_rle(GLIDER).unwrap();
+        assert_eq!((glider.w, glider.h), (3, 3));
+        assert_eq!(glider.rule, Some(Rule::parse("B3/S23").unwrap()));
+        assert_eq!(glider.runs, vec![(1, 0, 1, 1), (2, 1, 1, 1), (0, 2, 3, 1)]);
+        let field = glider.to_field(5, 5, Placement::At(1, 1));
+        let live: Vec<(usize, usize)> = (0..25).map(|i| (i%5, i/5)).filter(|&(x, y)| field.get(x, y)).collect();
+        assert_eq!(live, vec![(2, 1), (3, 2), (1, 3), (2, 3), (3, 3)]);
+        assert_eq!(glider.to_rle(), "x = 3, y = 3, rule = B3/S23\nbo$2bo$3o!\n");
+    }
+
+    #[test]
+    fn rle_round_trip () {
+        let mut field = Field::new(100, 7);
+        for x in 0..100 {
+            field.set(x, (x*x) % 7, x % 3 != 0);
+        }
+        let rule = Rule::parse("B36/S23").unwrap();
+        let rle = Pattern::from_field(&field, Some(rule)).to_rle();
+        assert!(rle.lines().all(|line| line.len() <= RLE_LINE_LEN));
+        let pattern = Pattern::from_rle(&rle).unwrap();
+        assert_eq!(pattern.rule, Some(rule));
+        assert!(pattern.to_field(100, 7, Placement::At(0, 0)).data() == field.data());
+    }
+
+    // states beyond 24 have a prefix, e.g. "pA" for 25
+    #[test]
+    fn rle_states () {
+        let mut field = Field::new(60, 3);
+        field.enable_states();
+        for x in 0..60 {
+            field.set_state(x, x % 3, (x % 59 + 1) as u8);
+        }
+        let rule = Rule::parse("B2/S/C60").unwrap();
+        let rle = Pattern::from_field(&field, Some(rule)).to_rle();
+        assert!(rle.contains("pA") && rle.contains("qJ"));
+        let pattern = Pattern::from_rle(&rle).unwrap();
+        let mut copy = Field::new(60, 3);
+        copy.enable_states();
+        pattern.place(&mut copy, 0, 0);
+        assert!(copy.states() == field.states());
+        let pattern = Pattern::from_rle("x = 4, y = 1, rule = B2/S/C40\n.2AyO!").unwrap();
+        assert_eq!(pattern.runs, vec![(1, 0, 2, 1), (3, 0, 1, 255)]);
+        assert_eq!(Pattern::from_rle("x = 4, y = 1\n2A.pB!").unwrap().runs, vec![(0, 0, 2, 1), (3, 0, 1, 26)]);
+    }
+
+    #[test]
+    fn rle_errors () {
+        assert_eq!(Pattern::from_rle("bo!").err(), Some(PatternError::InvalidHeader { line: 1 }));
+        assert_eq!(Pattern::from_rle("x = 3, z = 3\nbo!").err(), Some(PatternError::InvalidHeader { line: 1 }));
+        assert_eq!(Pattern::from_rle("x = 3, y = 3\n\nbo$\nbqb!").err(), Some(PatternError::UnexpectedChar { c: 'q', line: 4 }));
+        assert_eq!(Pattern::from_rle("x = 3, y = 1\nyX!").err(), Some(PatternError::InvalidState { line: 2 }));
+        assert!(matches!(
+            Pattern::from_rle("x = 3, y = 3, rule = B3/S29").err(),
+            Some(PatternError::InvalidRule { line: 1, .. })
+        ));
+        // huge runs are rejected before they are stored
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n99999999999o!").err(), Some(PatternError::TooLarge { line: 2 }));
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n99999999999999999999999o!").err(), Some(PatternError::TooLarge { line: 2 }));
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n9999999$9999999o!").err(), Some(PatternError::TooLarge { line: 2 }));
+        assert_eq!(Pattern::from_rle("x = 999999, y = 999999\no!").err(), Some(PatternError::TooLarge { line: 1 }));
+        // but big ones aren't expanded
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n10000000o!").unwrap().runs, vec![(0, 0, 10000000, 1)]);
+        // multi-state fields take a byte per cell
+        assert!(Pattern::from_rle("x = 100000, y = 100000, rule = B3/S23\no!").is_ok());
+        assert_eq!(Pattern::from_rle("x = 100000, y = 100000, rule = B3/S23/C3\no!").err(), Some(PatternError::TooLarge { line: 1 }));
+        assert!(Pattern::from_rle("x = 0, y = 0\n10000000000o!").is_ok());
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n10000000000B!").err(), Some(PatternError::TooLarge { line: 2 }));
+        assert_eq!(Pattern::from_rle("x = 100000, y = 100000\nA$B!").err(), Some(PatternError::TooLarge { line: 2 }));
+    }
+
+    #[test]
+    fn empty () {
+        assert_eq!(Pattern::from_rle("x = 0, y = 0\n!").err(), Some(PatternError::Empty));
+        assert_eq!(Pattern::from_rle("x = 0, y = 3\n!").err(), Some(PatternError::Empty));
+        assert_eq!(Pattern::from_cells("").err(), Some(PatternError::Empty));
+        assert_eq!(Pattern::from_cells("!Name: nothing\n...\n...").err(), Some(PatternError::Empty));
+        assert_eq!(Pattern::from_macrocell("[M2] (golly 4.2)\n#R B3/S23\n").err(), Some(PatternError::Empty));
+        assert_eq!(Pattern::from_macrocell("[M2]\n4 0 0 0 0\n").err(), Some(PatternError::Empty));
+    }
+
+    #[test]
+    fn cells () {
+        let glider = Pattern::from_cells("!Name: Glider\n.O\n..O\nOOO\n").unwrap();
+        assert_eq!((glider.w, glider.h), (3, 3));
+        assert_eq!(glider.runs, vec![(1, 0, 1, 1), (2, 1, 1, 1), (0, 2, 3, 1)]);
+    }
+
+    #[test]
+    fn macrocell () {
+        // a glider in the SE quadrant of a 16×16 node
+        let glider = Pattern::from_macrocell("[M2] (golly 4.2)\n#R B3/S23\n$$$$$.*$..*$***$\n4 0 0 0 1\n").unwrap();
+        assert_eq!((glider.w, glider.h), (3, 3));
+        assert_eq!(glider.rule, Some(Rule::parse("B3/S23").unwrap()));
+        let field = glider.to_field(3, 3, Placement::At(0, 0));
+        let live: Vec<(usize, usize)> = (0..9).map(|i| (i%3, i/3)).filter(|&(x, y)| field.get(x, y)).collect();
+        assert_eq!(live, vec![(1, 0), (2, 1), (0, 2), (1, 2), (2, 2)]);
+        // states
+        let pattern = Pattern::from_macrocell("[M2]\n#R B2/S/C3\n1 2 0 0 1\n").unwrap();
+        assert_eq!((pattern.w, pattern.h), (2, 2));
+        assert_eq!(pattern.runs, vec![(0, 0, 1, 2), (1, 1, 1, 1)]);
+        assert_eq!(Pattern::from_macrocell("[M2]\n4 0 1 0 0\n").err(), Some(PatternError::InvalidNode { line: 2 }));
+    }
+
+    // quadtrees can hold far more cells than fit into memory
+    #[test]
+    fn macrocell_huge () {
+        // a leaf with one cell in each corner, repeated 4^37 times
+        let mut mc = String::from("[M2]\n*......*$$$$$$$*......*$\n");
+        for level in 4..=40 {
+            mc += &format!("{} {} {} {} {}\n", level, level - 3, level - 3, level - 3, level - 3);
+        }
+        let root_line = mc.lines().count();
+        assert_eq!(Pattern::from_macrocell(&mc).err(), Some(PatternError::TooLarge { line: root_line }));
+        // just the part that ends up in the field is expanded
+        let pattern = Pattern::from_macrocell_within(&mc, 10, 9, Placement::At(-5, 0)).unwrap();
+        assert_eq!((pattern.w, pattern.h), (1 << 40, 1 << 40));
+        assert_eq!(pattern.runs, vec![(7, 0, 2, 1), (7, 7, 2, 1), (7, 8, 2, 1)]);
+        let field = pattern.to_field(10, 9, Placement::At(-5, 0));
+        let live: Vec<(usize, usize)> = (0..90).map(|i| (i%10, i/10)).filter(|&(x, y)| field.get(x, y)).collect();
+        assert_eq!(live, vec![(2, 0), (3, 0), (2, 7), (3, 7), (2, 8), (3, 8)]);
+        // the centre, where four leaves meet: 4 rows of 3 runs
+        let pattern = Pattern::from_macrocell_within(&mc, 16, 16, Placement::Centred).unwrap();
+        assert_eq!(pattern.runs.len(), 12);
+    }
+}
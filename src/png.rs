@@ -0,0 +1,180 @@
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+use sdl2::image::SaveSurface;
+
+use automata::AutomataError;
+use automata::Field;
+
+
+/*
+Seed and snapshot images. These only need SDL2_image, not the SDL video
+subsystem, so they also work in headless mode.
+*/
+
+/*
+How seed_png turns pixels into cells: whatever the pixel format (grey, RGB,
+RGBA, palette, 1 to 16 bits), a pixel is alive if its luminance is below the
+threshold, e.g. black on white. Seeds drawn the other way round need "invert".
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct SeedOptions {
+    // 0 to 255
+    pub threshold: u8,
+    pub invert: bool,
+    // (mostly) transparent pixels are dead, whatever their colour
+    pub alpha_mask: bool,
+}
+
+impl Default for SeedOptions
+{
+    fn default () -> Self {
+        SeedOptions {
+            threshold: 128,
+            invert: false,
+            alpha_mask: true,
+        }
+    }
+}
+
+impl SeedOptions
+{
+    // "threshold", "invert" and "alpha_mask" in seed.json, all optional
+    pub fn from_seed (seed_json: &json::JsonValue) -> Result<SeedOptions, Box<dyn std::error::Error>>
+    {
+        let default = SeedOptions::default();
+        let threshold = if seed_json["threshold"].is_null() {
+            default.threshold
+        } else {
+            seed_json["threshold"].as_u8().ok_or_else(|| AutomataError::new(
+                "\"threshold\" in seed.json must be a number from 0 to 255."
+            ))?
+        };
+        Ok(SeedOptions {
+            threshold,
+            invert: seed_json["invert"].as_bool().unwrap_or(default.invert),
+            alpha_mask: seed_json["alpha_mask"].as_bool().unwrap_or(default.alpha_mask),
+        })
+    }
+}
+
+pub fn seed_png (path: &str, options: &SeedOptions) -> Result<Field, Box<dyn std::error::Error>>
+{
+    let decode_error = |err: String| AutomataError::new(
+        &format!("Cannot decode the image \"{}\": {}", path, err)
+    );
+    let surf: Surface = sdl2::image::LoadSurface::from_file(path).map_err(decode_error)?;
+    // 4 bytes per pixel, in the order R, G, B, A
+    let surf = surf.convert_format(PixelFormatEnum::RGBA32).map_err(decode_error)?;
+    let w = surf.width() as usize;
+    let h = surf.height() as usize;
+    let pitch = surf.pitch() as usize;
+    let mut field = Field::new(w, h);
+    surf.with_lock(|pixels| {
+        for y in 0..h {
+            for x in 0..w {
+                let pixel = &pixels[y*pitch + x*4 .. y*pitch + x*4 + 4];
+                let (r, g, b, a) = (pixel[0] as u32, pixel[1] as u32, pixel[2] as u32, pixel[3]);
+                // Rec. 601 luma
+                let luminance = (299*r + 587*g + 114*b) / 1000;
+                let mut alive = luminance < options.threshold as u32;
+                if options.invert {
+                    alive = !alive;
+                }
+                if options.alpha_mask && a < 128 {
+                    alive = false;
+                }
+                field.set(x, y, alive);
+            }
+        }
+    });
+    Ok(field)
+}
+
+// "states" is the number of states of the rule (2, unless Generations).
+pub fn snapshot_png (field: &Field, states: usize, path: &str)
+{
+    let (w, h) = (field.w, field.h);
+    let surf = Surface::new(
+        w as u32,
+        h as u32,
+        sdl2::pixels::PixelFormatEnum::RGB332 //1 byte per pixel
+    ).unwrap();
+    let pitch = surf.pitch();
+    unsafe {
+        let pixels = (*surf.raw()).pixels as *mut u8;
+        for y in 0..h {
+            for x in 0..w {
+                let pixel_i = y*(pitch as usize) + x;
+                *pixels.add(pixel_i) = match field.state(x,y) {
+                    0 => 255,
+                    1 => 0,
+                    state => {
+                        // RGB332
+                        let (r, g, b) = dying_color(state, states);
+                        (r & 0xe0) | ((g >> 3) & 0x1c) | (b >> 6)
+                    },
+                };
+            }
+        }
+    }
+    surf.save(path).unwrap();
+}
+
+/*
+The reverse of snapshot_png, e.g. for resuming a run: unlike seed_png, this
+also restores the dying states of Generations rules from their colours.
+*/
+pub fn load_snapshot_png (path: &str, states: usize) -> Result<Field, Box<dyn std::error::Error>>
+{
+    let decode_error = |err: String| AutomataError::new(
+        &format!("Cannot decode the snapshot \"{}\": {}", path, err)
+    );
+    let surf: Surface = sdl2::image::LoadSurface::from_file(path).map_err(decode_error)?;
+    let surf = surf.convert_format(PixelFormatEnum::RGBA32).map_err(decode_error)?;
+    let w = surf.width() as usize;
+    let h = surf.height() as usize;
+    let pitch = surf.pitch() as usize;
+    // the colours as RGB332 keeps them; with many states, some dying states
+    // share a colour, so RLE snapshots are the better choice for those
+    let rgb332 = |r: u8, g: u8, b: u8| (r >> 5, g >> 5, b >> 6);
+    let dying: Vec<(u8, u8, u8)> = (2..states)
+        .map(|state| {
+            let (r, g, b) = dying_color(state as u8, states);
+            rgb332(r, g, b)
+        })
+        .collect();
+    let mut field = Field::new(w, h);
+    if states > 2 {
+        field.enable_states();
+    }
+    surf.with_lock(|pixels| {
+        for y in 0..h {
+            for x in 0..w {
+                let pixel = &pixels[y*pitch + x*4 .. y*pitch + x*4 + 4];
+                let color = rgb332(pixel[0], pixel[1], pixel[2]);
+                match dying.iter().position(|&dying_color| dying_color == color) {
+                    Some(dying_i) if color != (0, 0, 0) && color != (7, 7, 3) =>
+                        field.set_state(x, y, dying_i as u8 + 2),
+                    _ => field.set(x, y, color == (0, 0, 0)),
+                }
+            }
+        }
+    });
+    Ok(field)
+}
+
+/*
+The colour of a dying cell (state 2 and up, see rule.rs) of a Generations rule,
+from orange right after dying to a dark red just before becoming dead (0).
+*/
+pub fn dying_color (state: u8, states: usize) -> (u8, u8, u8)
+{
+    // 0.0 for state 2, 1.0 for state states-1
+    let f = if states > 3 {
+        (state as f64 - 2.0) / (states as f64 - 3.0)
+    } else {
+        0.0
+    };
+    let mix = |a: f64, b: f64| (a + (b - a)*f).round() as u8;
+    (mix(255.0, 96.0), mix(160.0, 0.0), 0)
+}
@@ -0,0 +1,921 @@
+/*
+The "rule" is the transition function from a cell's 3×3 neighbourhood to its
+next state. In "env" values, the neighbourhood is numbered like this:
+    0 1 2      NW N  NE
+    3 4 5  ->  W  C  E
+    6 7 8      SW S  SE
+Rulestrings are accepted in these notations:
+    B3/S23, b3s23, B3S23   outer totalistic, born counts first
+    S23/B3, s23b3          outer totalistic, survival counts first
+    23/3                   the older S/B notation, without letters
+    B2n3/S23-q             isotropic non-totalistic (Hensel notation)
+    MAP...                 Golly's base64 encoding of all 512 transitions
+The canonical notation, as used by Display, is "B3/S23" or "B2n3/S23-q",
+or "MAP..." for transition functions that aren't isotropic.
+Generations rules have more than 2 states: a live cell that doesn't survive
+goes through the dying states 2, 3, ... before it's dead (0) again. Dying
+cells don't count as neighbours, and can't be born. The number of states
+comes after the transitions, as in "B2/S/C3" or "MAP.../C3", or in the
+S/B/C notation, as in "/2/3" (Brian's Brain) or "345/2/4" (Star Wars).
+Larger-than-Life rules count the live cells within a radius R instead, as in
+Golly's "R5,C0,M1,S34..58,B34..45,NM": M1 counts the cell itself, too; S and
+B are ranges of counts; and NM, NN or NC is the Moore (square), von Neumann
+(diamond) or circular neighbourhood. Only C0 and C2 (2 states) are supported.
+Outer totalistic rules may end in "H" or "V", as in "B2/S34H" or "B2/S013V",
+for Golly's emulation of the hexagonal or the von Neumann neighbourhood on
+the square grid: only 6 (all but NE and SW) or 4 (N, W, E, S) neighbours count.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub transition: Transition,
+    // 2, or more for Generations rules
+    pub states: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    // bitmasks over the neighbour counts 0..=8 (or 0..=6, 0..=4)
+    Totalistic { borns: u16, survives: u16, neighbourhood: Neighbourhood },
+    // per neighbour count, a bitmask over its letters (see HENSEL_LETTERS)
+    Isotropic { borns: [u16; 9], survives: [u16; 9] },
+    // bit i of the bitstring is the next state for MAP index i (see map_i)
+    Map([u8; 64]),
+    // inclusive ranges of counts; "middle": whether the cell counts itself
+    LargerThanLife {
+        radius: usize,
+        middle: bool,
+        survives: (usize, usize),
+        borns: (usize, usize),
+        neighbourhood: Neighbourhood,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Neighbourhood {
+    Moore,
+    VonNeumann,
+    Circular,
+    // only for outer totalistic rules, with the "H" suffix
+    Hexagonal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleError {
+    Empty,
+    // some character that has no place in a rulestring
+    UnexpectedChar { c: char, pos: usize },
+    // a neighbour count that's not in 0..=8
+    InvalidCount { c: char, pos: usize },
+    // a Hensel letter that doesn't exist for the count before it
+    InvalidLetter { c: char, count: usize, pos: usize },
+    // counts before any "B" or "S", e.g. "3B"
+    CountsWithoutPart { pos: usize },
+    DuplicatePart { part: char, pos: usize },
+    MissingPart { part: char },
+    // the S/B notation needs exactly one slash
+    MissingSlash,
+    // not 512 bits of base64 after "MAP"
+    InvalidMap,
+    // the number of states is not in 2..=256
+    InvalidStates { pos: usize },
+    // Larger-than-Life: the radius is not in 1..=500
+    InvalidRadius { pos: usize },
+    // Larger-than-Life: not "min..max"
+    InvalidRange { part: char, pos: usize },
+    // Larger-than-Life: more than 2 states
+    UnsupportedStates { pos: usize },
+    // "H" or "V" after Hensel letters
+    UnsupportedNeighbourhood { pos: usize },
+    // e.g. "B7" with "H"
+    CountTooHigh { count: usize, max: usize },
+}
+
+impl std::fmt::Display for RuleError {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            RuleError::Empty =>
+                write!(f, "The rulestring is empty."),
+            RuleError::UnexpectedChar { c, pos } =>
+                write!(f, "Unexpected character '{}' at position {} of the rulestring.", c, pos),
+            RuleError::InvalidCount { c, pos } =>
+                write!(f, "Invalid neighbour count '{}' at position {} of the rulestring (expected 0 to 8).", c, pos),
+            RuleError::InvalidLetter { c, count, pos } =>
+                write!(
+                    f,
+                    "Invalid letter '{}' for neighbour count {} at position {} of the rulestring (expected one of \"{}\").",
+                    c, count, pos, hensel_letters(count)
+                ),
+            RuleError::CountsWithoutPart { pos } =>
+                write!(f, "Neighbour counts at position {} of the rulestring belong to neither B nor S.", pos),
+            RuleError::DuplicatePart { part, pos } =>
+                write!(f, "'{}' appears a second time at position {} of the rulestring.", part, pos),
+            RuleError::MissingPart { part } =>
+                write!(f, "The rulestring lacks the '{}' part.", part),
+            RuleError::MissingSlash =>
+                write!(f, "A rulestring without B and S needs the form survivals/births, e.g. \"23/3\"."),
+            RuleError::InvalidMap =>
+                write!(f, "A MAP rulestring needs 512 bits in base64 (86 characters) after \"MAP\"."),
+            RuleError::InvalidStates { pos } =>
+                write!(f, "Invalid number of states at position {} of the rulestring (expected 2 to 256).", pos),
+            RuleError::InvalidRadius { pos } =>
+                write!(f, "Invalid radius at position {} of the rulestring (expected 1 to {}).", pos, MAX_RADIUS),
+            RuleError::InvalidRange { part, pos } =>
+                write!(f, "Invalid range for '{}' at position {} of the rulestring (expected e.g. \"{}34..58\").", part, pos, part),
+            RuleError::UnsupportedStates { pos } =>
+                write!(f, "Larger-than-Life rules with more than 2 states are not supported (position {} of the rulestring).", pos),
+            RuleError::UnsupportedNeighbourhood { pos } =>
+                write!(f, "The suffix at position {} of the rulestring is only supported for outer totalistic rules.", pos),
+            RuleError::CountTooHigh { count, max } =>
+                write!(f, "The neighbour count {} is impossible with the suffix of the rulestring (at most {}).", count, max),
+        }
+    }
+}
+impl std::error::Error for RuleError {}
+
+/*
+Hensel notation: the letters for each neighbour count up to 4, and one
+neighbourhood (env value) for each letter. The others are rotations and
+reflections of these; the letters for 5 to 8 neighbours are the same as for
+8-5 to 8-8 neighbours, with the live and dead neighbours swapped.
+*/
+const HENSEL_LETTERS: [&str; 5] = ["", "ce", "ceaikn", "ceaiknjqry", "ceaiknjqrytwz"];
+const HENSEL_ENVS: [&[u16]; 5] = [
+    &[0],
+    &[1, 2],
+    &[5, 10, 3, 40, 33, 68],
+    &[69, 42, 11, 7, 98, 13, 14, 70, 41, 97],
+    &[325, 170, 15, 45, 99, 71, 106, 102, 43, 101, 105, 78, 108],
+];
+// all but the center
+const NEIGHBOURS: u16 = 0x1ef;
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+// Golly's limit for Larger-than-Life
+const MAX_RADIUS: usize = 500;
+
+fn hensel_letters (count: usize) -> &'static str {
+    HENSEL_LETTERS[count.min(8 - count)]
+}
+
+// the bitmask for a count without letters; 0 and 8 neighbours have just one neighbourhood
+fn all_letters (count: usize) -> u16 {
+    (1 << hensel_letters(count).len().max(1)) - 1
+}
+
+// rotates the neighbourhood by 90° or mirrors it horizontally
+fn rotate (env: u16) -> u16 {
+    (0..9).fold(0, |result, i| result | (((env >> i) & 1) << ((i%3)*3 + 2 - i/3)))
+}
+fn mirror (env: u16) -> u16 {
+    (0..9).fold(0, |result, i| result | (((env >> i) & 1) << ((i/3)*3 + 2 - i%3)))
+}
+
+// which of the Hensel letters for its neighbour count an env has
+fn hensel_letter_i (env: u16) -> usize {
+    let count = (env & NEIGHBOURS).count_ones() as usize;
+    let (envs, mut env) = if count <= 4 {
+        (HENSEL_ENVS[count], env & NEIGHBOURS)
+    } else {
+        (HENSEL_ENVS[8 - count], !env & NEIGHBOURS)
+    };
+    for _ in 0..2 {
+        for _ in 0..4 {
+            if let Some(letter_i) = envs.iter().position(|&e| e == env) {
+                return letter_i;
+            }
+            env = rotate(env);
+        }
+        env = mirror(env);
+    }
+    unreachable!("Every neighbourhood has a Hensel letter.");
+}
+
+// Golly's MAP order: NW is the most significant bit, SE the least significant
+fn map_i (env: u16) -> usize {
+    (0..9).fold(0, |result, i| result | (((env as usize >> i) & 1) << (8 - i)))
+}
+
+impl Rule
+{
+    pub fn parse (rulestring: &str) -> Result<Rule, RuleError>
+    {
+        let rulestring = rulestring.trim();
+        if rulestring.is_empty() {
+            return Err(RuleError::Empty);
+        }
+        if !rulestring.chars().any(|c| c.is_ascii_alphabetic()) {
+            return Rule::parse_sbc(rulestring);
+        }
+        let mut chars = rulestring.chars();
+        if chars.next().unwrap().eq_ignore_ascii_case(&'R')
+                && chars.next().is_some_and(|c| c.is_ascii_digit()) {
+            return Rule::parse_ltl(rulestring);
+        }
+        if let Some(payload) = rulestring.strip_prefix("MAP") {
+            return Rule::parse_map(payload);
+        }
+        // "H" or "V" at the end
+        let suffix_pos = rulestring.len() - 1;
+        let neighbourhood = match rulestring.as_bytes()[suffix_pos].to_ascii_uppercase() {
+            b'H' => Some(Neighbourhood::Hexagonal),
+            b'V' => Some(Neighbourhood::VonNeumann),
+            _ => None,
+        };
+        if let Some(neighbourhood) = neighbourhood {
+            return Rule::parse(&rulestring[..suffix_pos])?
+                .with_neighbourhood(neighbourhood, suffix_pos);
+        }
+        // "/C..." at the end
+        let (transitions, states) = match rulestring.to_ascii_uppercase().rfind("/C") {
+            Some(slash_pos) => (
+                &rulestring[..slash_pos],
+                Rule::states(&rulestring[slash_pos+2..], slash_pos+2)?,
+            ),
+            None => (rulestring, 2),
+        };
+        let transition = if transitions.ends_with(['H', 'h', 'V', 'v']) {
+            // e.g. "B2/S34H/C3"
+            Rule::parse(transitions)?.transition
+        } else {
+            Transition::parse_bs(transitions)?
+        };
+        Ok(Rule { transition, states })
+    }
+
+    /*
+    "MAP..." (without the "MAP"): exactly 86 base64 characters, maybe padded
+    with '=', and then maybe "/C..."; base64 has '/', so the payload may
+    contain "/C", too.
+    */
+    fn parse_map (payload: &str) -> Result<Rule, RuleError>
+    {
+        let base64 = payload.get(..86).ok_or(RuleError::InvalidMap)?;
+        let transition = Transition::parse_map(base64)?;
+        let suffix = payload[86..].trim_start_matches('=');
+        // the position in the rulestring, after "MAP"
+        let suffix_pos = 3 + payload.len() - suffix.len();
+        let states = match suffix.get(..2) {
+            _ if suffix.is_empty() => 2,
+            Some("/C") | Some("/c") => Rule::states(&suffix[2..], suffix_pos + 2)?,
+            _ => return Err(RuleError::UnexpectedChar {
+                c: suffix.chars().next().unwrap(),
+                pos: suffix_pos,
+            }),
+        };
+        Ok(Rule { transition, states })
+    }
+
+    // for the "H" and "V" suffixes
+    fn with_neighbourhood (mut self, new: Neighbourhood, pos: usize) -> Result<Rule, RuleError>
+    {
+        match self.transition {
+            Transition::Totalistic { borns, survives, ref mut neighbourhood }
+                    if *neighbourhood == Neighbourhood::Moore => {
+                let max = new.mask_3x3().count_ones() as usize;
+                let too_high = (borns | survives) >> (max + 1);
+                if too_high != 0 {
+                    return Err(RuleError::CountTooHigh {
+                        count: max + 1 + too_high.trailing_zeros() as usize,
+                        max,
+                    });
+                }
+                *neighbourhood = new;
+                Ok(self)
+            },
+            _ => Err(RuleError::UnsupportedNeighbourhood { pos }),
+        }
+    }
+
+    // "23/3", "345/2/4"
+    fn parse_sbc (rulestring: &str) -> Result<Rule, RuleError>
+    {
+        let mut counts = [0u16; 2];
+        let mut part_i = 0;
+        for (pos, c) in rulestring.chars().enumerate() {
+            match c {
+                '/' if part_i == 0 => part_i = 1,
+                '/' if part_i == 1 => {
+                    return Ok(Rule {
+                        transition: Transition::Totalistic {
+                            borns: counts[1],
+                            survives: counts[0],
+                            neighbourhood: Neighbourhood::Moore,
+                        },
+                        states: Rule::states(&rulestring[pos+1..], pos+1)?,
+                    });
+                },
+                '0' ..= '9' => counts[part_i] |= 1 << Transition::count(c, pos)?,
+                _ => return Err(RuleError::UnexpectedChar { c, pos }),
+            }
+        }
+        if part_i == 0 {
+            return Err(RuleError::MissingSlash);
+        }
+        Ok(Rule {
+            transition: Transition::Totalistic {
+                borns: counts[1],
+                survives: counts[0],
+                neighbourhood: Neighbourhood::Moore,
+            },
+            states: 2,
+        })
+    }
+
+    // "R5,C0,M1,S34..58,B34..45,NM"
+    fn parse_ltl (rulestring: &str) -> Result<Rule, RuleError>
+    {
+        let mut radius: Option<usize> = None;
+        let mut states: Option<usize> = None;
+        let mut middle: Option<bool> = None;
+        let mut survives: Option<(usize, usize)> = None;
+        let mut borns: Option<(usize, usize)> = None;
+        let mut neighbourhood: Option<Neighbourhood> = None;
+        let mut pos = 0;
+        for item in rulestring.split(',') {
+            let item_pos = pos;
+            pos += item.chars().count() + 1;
+            let mut chars = item.chars();
+            let part = match chars.next() {
+                Some(c) => c.to_ascii_uppercase(),
+                None => return Err(RuleError::UnexpectedChar { c: ',', pos: item_pos }),
+            };
+            let value = chars.as_str();
+            let value_pos = item_pos + 1;
+            let duplicate = match part {
+                'R' => radius.is_some(),
+                'C' => states.is_some(),
+                'M' => middle.is_some(),
+                'S' => survives.is_some(),
+                'B' => borns.is_some(),
+                'N' => neighbourhood.is_some(),
+                c => return Err(RuleError::UnexpectedChar { c, pos: item_pos }),
+            };
+            if duplicate {
+                return Err(RuleError::DuplicatePart { part, pos: item_pos });
+            }
+            match part {
+                'R' => radius = Some(match value.parse::<usize>() {
+                    Ok(r) if (1..=MAX_RADIUS).contains(&r) => r,
+                    _ => return Err(RuleError::InvalidRadius { pos: value_pos }),
+                }),
+                'C' => states = Some(match value.parse::<usize>() {
+                    Ok(c) if c <= 2 => 2,
+                    Ok(c) if c <= 256 => return Err(RuleError::UnsupportedStates { pos: value_pos }),
+                    _ => return Err(RuleError::InvalidStates { pos: value_pos }),
+                }),
+                'M' => middle = Some(match value {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(RuleError::UnexpectedChar {
+                        c: value.chars().next().unwrap_or(','),
+                        pos: value_pos,
+                    }),
+                }),
+                'S' | 'B' => {
+                    let range = value.split_once("..")
+                        .and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)))
+                        .ok_or(RuleError::InvalidRange { part, pos: value_pos })?;
+                    if part == 'S' { survives = Some(range) } else { borns = Some(range) }
+                },
+                _ /*'N'*/ => neighbourhood = Some(match value.to_ascii_uppercase().as_str() {
+                    "M" => Neighbourhood::Moore,
+                    "N" => Neighbourhood::VonNeumann,
+                    "C" => Neighbourhood::Circular,
+                    _ => return Err(RuleError::UnexpectedChar {
+                        c: value.chars().next().unwrap_or(','),
+                        pos: value_pos,
+                    }),
+                }),
+            }
+        }
+        Ok(Rule {
+            transition: Transition::LargerThanLife {
+                radius: radius.ok_or(RuleError::MissingPart { part: 'R' })?,
+                middle: middle.ok_or(RuleError::MissingPart { part: 'M' })?,
+                survives: survives.ok_or(RuleError::MissingPart { part: 'S' })?,
+                borns: borns.ok_or(RuleError::MissingPart { part: 'B' })?,
+                neighbourhood: neighbourhood.unwrap_or(Neighbourhood::Moore),
+            },
+            states: states.unwrap_or(2),
+        })
+    }
+
+    // Hexagonal rules are drawn sheared (see window.rs).
+    pub fn neighbourhood (&self) -> Neighbourhood
+    {
+        match self.transition {
+            Transition::Totalistic { neighbourhood, .. } => neighbourhood,
+            Transition::LargerThanLife { neighbourhood, .. } => neighbourhood,
+            _ => Neighbourhood::Moore,
+        }
+    }
+
+    // Larger-than-Life rules beyond radius 1 don't fit into the lookup table.
+    pub fn radius (&self) -> usize
+    {
+        match self.transition {
+            Transition::LargerThanLife { radius, .. } => radius,
+            _ => 1,
+        }
+    }
+
+    /*
+    The bitmasks over the birth and survival counts of a 2-state outer
+    totalistic rule with the Moore neighbourhood (like B3/S23), which can be
+    played by counting the neighbours instead of looking them up (see
+    "play_sliced" in kernels.cl).
+    */
+    pub fn outer_totalistic (&self) -> Option<(u16, u16)>
+    {
+        match self.transition {
+            Transition::Totalistic { borns, survives, neighbourhood: Neighbourhood::Moore }
+                if self.states == 2 => Some((borns, survives)),
+            _ => None,
+        }
+    }
+
+    fn states (states_str: &str, pos: usize) -> Result<usize, RuleError>
+    {
+        match states_str.parse::<usize>() {
+            Ok(states) if (2..=256).contains(&states) => Ok(states),
+            _ => Err(RuleError::InvalidStates { pos }),
+        }
+    }
+
+    // Is the center cell alive in the next generation? (see Transition::next)
+    pub fn next (&self, env: u16) -> bool
+    {
+        self.transition.next(env)
+    }
+
+    /*
+    The next state of a cell in the given state, for Generations rules.
+    In env, only the cells in state 1 are set.
+    */
+    pub fn next_state (&self, state: u8, env: u16) -> u8
+    {
+        match state {
+            0 => self.next(env) as u8,
+            1 if self.next(env | (1 << 4)) => 1,
+            _ if state as usize + 1 >= self.states => 0,
+            _ => state + 1,
+        }
+    }
+}
+
+impl Transition
+{
+    /*
+    Is the center cell alive in the next generation?
+    For Larger-than-Life rules, only the 3×3 part of the neighbourhood is
+    seen here, which is all of it for radius 1 (see Rule::radius).
+    */
+    pub fn next (&self, env: u16) -> bool
+    {
+        let count = (env & NEIGHBOURS).count_ones() as usize;
+        let alive = (env >> 4) & 1 != 0;
+        match *self {
+            Transition::Totalistic { borns, survives, neighbourhood } => {
+                let count = (env & neighbourhood.mask_3x3()).count_ones();
+                let counts = if alive { survives } else { borns };
+                (counts >> count) & 1 != 0
+            },
+            Transition::Isotropic { borns, survives } => {
+                let letters = if alive { survives[count] } else { borns[count] };
+                (letters >> hensel_letter_i(env)) & 1 != 0
+            },
+            Transition::Map(bits) => {
+                let i = map_i(env);
+                (bits[i/8] >> (7 - i%8)) & 1 != 0
+            },
+            Transition::LargerThanLife { middle, survives, borns, neighbourhood, .. } => {
+                let count = (env & neighbourhood.mask_3x3()).count_ones() as usize
+                    + (alive && middle) as usize;
+                let (min, max) = if alive { survives } else { borns };
+                (min..=max).contains(&count)
+            },
+        }
+    }
+
+    // "B3/S23", "B2n3/S23-q" and their variants
+    fn parse_bs (rulestring: &str) -> Result<Transition, RuleError>
+    {
+        let mut borns: Option<[u16; 9]> = None;
+        let mut survives: Option<[u16; 9]> = None;
+        let mut part: Option<char> = None;
+        // the count that Hensel letters refer to, whether they're negated ("-"),
+        // and whether there have been letters already
+        let mut count: Option<usize> = None;
+        let mut negated = false;
+        let mut letters_seen = false;
+        let mut chars = rulestring.chars().enumerate().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c.to_ascii_uppercase() {
+                'B' | 'S' => {
+                    let part_c = c.to_ascii_uppercase();
+                    let counts = if part_c == 'B' { &mut borns } else { &mut survives };
+                    if counts.is_some() {
+                        return Err(RuleError::DuplicatePart { part: part_c, pos });
+                    }
+                    *counts = Some([0; 9]);
+                    part = Some(part_c);
+                    count = None;
+                },
+                '/' if part.is_some() => count = None,
+                '0' ..= '9' => {
+                    let counts = match part {
+                        Some('B') => borns.as_mut().unwrap(),
+                        Some(_) => survives.as_mut().unwrap(),
+                        None => return Err(RuleError::CountsWithoutPart { pos }),
+                    };
+                    let c_count = Transition::count(c, pos)?;
+                    counts[c_count] = all_letters(c_count);
+                    count = Some(c_count);
+                    negated = false;
+                    letters_seen = false;
+                    if let Some(&(minus_pos, '-')) = chars.peek() {
+                        chars.next();
+                        negated = true;
+                        // "-" without letters after it
+                        match chars.peek() {
+                            Some(&(_, letter)) if letter.is_ascii_lowercase() => {},
+                            _ => return Err(RuleError::InvalidLetter { c: '-', count: c_count, pos: minus_pos }),
+                        }
+                    }
+                },
+                _ if count.is_some() && c.is_ascii_lowercase() => {
+                    let c_count = count.unwrap();
+                    let letter_i = hensel_letters(c_count).find(c)
+                        .ok_or(RuleError::InvalidLetter { c, count: c_count, pos })?;
+                    let counts = if part == Some('B') {
+                        borns.as_mut().unwrap()
+                    } else {
+                        survives.as_mut().unwrap()
+                    };
+                    // letters list the neighbourhoods that count, or those that don't ("-")
+                    if !letters_seen {
+                        counts[c_count] = if negated { all_letters(c_count) } else { 0 };
+                        letters_seen = true;
+                    }
+                    if negated {
+                        counts[c_count] &= !(1 << letter_i);
+                    } else {
+                        counts[c_count] |= 1 << letter_i;
+                    }
+                },
+                _ => return Err(RuleError::UnexpectedChar { c, pos }),
+            }
+        }
+        let borns = borns.ok_or(RuleError::MissingPart { part: 'B' })?;
+        let survives = survives.ok_or(RuleError::MissingPart { part: 'S' })?;
+        Ok(Transition::isotropic_or_totalistic(borns, survives))
+    }
+
+    // the 86 base64 characters of a MAP rule (see Rule::parse_map)
+    fn parse_map (base64: &str) -> Result<Transition, RuleError>
+    {
+        let mut bits = [0u8; 64];
+        for (ci, c) in base64.bytes().enumerate() {
+            let v = BASE64.iter().position(|&b| b == c).ok_or(RuleError::InvalidMap)?;
+            for vi in 0..6 {
+                let bit_i = ci*6 + vi;
+                if bit_i < 512 && (v >> (5 - vi)) & 1 != 0 {
+                    bits[bit_i/8] |= 1 << (7 - bit_i%8);
+                }
+            }
+        }
+        Ok(Transition::Map(bits))
+    }
+
+    fn count (c: char, pos: usize) -> Result<usize, RuleError>
+    {
+        match c.to_digit(10) {
+            Some(count) if count <= 8 => Ok(count as usize),
+            _ => Err(RuleError::InvalidCount { c, pos }),
+        }
+    }
+
+    // Isotropic, unless every count has either all letters or none.
+    fn isotropic_or_totalistic (borns: [u16; 9], survives: [u16; 9]) -> Transition
+    {
+        let mut totalistic = [0u16; 2];
+        for (part_i, letters) in [borns, survives].iter().enumerate() {
+            for (count, &count_letters) in letters.iter().enumerate() {
+                if count_letters == all_letters(count) {
+                    totalistic[part_i] |= 1 << count;
+                } else if count_letters != 0 {
+                    return Transition::Isotropic { borns, survives };
+                }
+            }
+        }
+        Transition::Totalistic {
+            borns: totalistic[0],
+            survives: totalistic[1],
+            neighbourhood: Neighbourhood::Moore,
+        }
+    }
+}
+
+impl Neighbourhood
+{
+    /*
+    How far the neighbourhood reaches left and right in the row dy (0..=radius)
+    above or below the cell. Circular means within a distance of radius+0.5,
+    so that every row has at least the cell above or below.
+    */
+    pub fn extent (&self, radius: usize, dy: usize) -> usize
+    {
+        match *self {
+            Neighbourhood::Moore => radius,
+            Neighbourhood::VonNeumann => radius - dy,
+            Neighbourhood::Circular => {
+                let limit = radius*radius + radius - dy*dy;
+                let mut dx = 0;
+                while (dx+1)*(dx+1) <= limit {
+                    dx += 1;
+                }
+                dx
+            },
+            Neighbourhood::Hexagonal =>
+                unreachable!("Larger-than-Life rules have no hexagonal neighbourhood."),
+        }
+    }
+
+    // the neighbours within radius 1, as bits of an env value
+    pub fn mask_3x3 (&self) -> u16
+    {
+        match *self {
+            Neighbourhood::Moore | Neighbourhood::Circular => NEIGHBOURS,
+            Neighbourhood::VonNeumann => 0x0aa,
+            Neighbourhood::Hexagonal => NEIGHBOURS & !0x044,
+        }
+    }
+}
+
+impl std::str::FromStr for Rule {
+    type Err = RuleError;
+    fn from_str (rulestring: &str) -> Result<Rule, RuleError> {
+        Rule::parse(rulestring)
+    }
+}
+
+// the counts of one part, e.g. "23-q"
+fn fmt_isotropic_part (f: &mut std::fmt::Formatter, letters: &[u16; 9]) -> std::fmt::Result {
+    for (count, &present) in letters.iter().enumerate() {
+        let count_letters = hensel_letters(count);
+        if present == 0 {
+            continue;
+        }
+        write!(f, "{}", count)?;
+        if present == all_letters(count) {
+            continue;
+        }
+        // whichever is shorter: the letters, or "-" and the missing letters
+        let negated = present.count_ones() as usize > count_letters.len() / 2;
+        if negated {
+            write!(f, "-")?;
+        }
+        for (letter_i, letter) in count_letters.chars().enumerate() {
+            if ((present >> letter_i) & 1 != 0) != negated {
+                write!(f, "{}", letter)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.transition)?;
+        if self.states > 2 {
+            write!(f, "/C{}", self.states)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Transition {
+    fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Transition::Totalistic { borns, survives, neighbourhood } => {
+                write!(f, "B")?;
+                for count in 0..9 {
+                    if (borns >> count) & 1 != 0 {
+                        write!(f, "{}", count)?;
+                    }
+                }
+                write!(f, "/S")?;
+                for count in 0..9 {
+                    if (survives >> count) & 1 != 0 {
+                        write!(f, "{}", count)?;
+                    }
+                }
+                match neighbourhood {
+                    Neighbourhood::Hexagonal => write!(f, "H"),
+                    Neighbourhood::VonNeumann => write!(f, "V"),
+                    _ => Ok(()),
+                }
+            },
+            Transition::Isotropic { borns, survives } => {
+                write!(f, "B")?;
+                fmt_isotropic_part(f, &borns)?;
+                write!(f, "/S")?;
+                fmt_isotropic_part(f, &survives)
+            },
+            Transition::Map(bits) => {
+                write!(f, "MAP")?;
+                for ci in 0..86 {
+                    let mut v = 0;
+                    for vi in 0..6 {
+                        let bit_i = ci*6 + vi;
+                        if bit_i < 512 && (bits[bit_i/8] >> (7 - bit_i%8)) & 1 != 0 {
+                            v |= 1 << (5 - vi);
+                        }
+                    }
+                    write!(f, "{}", BASE64[v] as char)?;
+                }
+                Ok(())
+            },
+            Transition::LargerThanLife { radius, middle, survives, borns, neighbourhood } => {
+                write!(
+                    f,
+                    "R{},C0,M{},S{}..{},B{}..{},N{}",
+                    radius, middle as u8, survives.0, survives.1, borns.0, borns.1,
+                    match neighbourhood {
+                        Neighbourhood::Moore => 'M',
+                        Neighbourhood::VonNeumann => 'N',
+                        Neighbourhood::Circular => 'C',
+                        Neighbourhood::Hexagonal => 'H',
+                    }
+                )
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use automata::Table;
+
+    fn life () -> Rule {
+        Rule {
+            transition: Transition::Totalistic {
+                borns: 1 << 3,
+                survives: 1 << 2 | 1 << 3,
+                neighbourhood: Neighbourhood::Moore,
+            },
+            states: 2,
+        }
+    }
+
+    #[test]
+    fn notations () {
+        for rulestring in ["B3/S23", "b3s23", "B3S23", "S23/B3", "23/3", "  B3/S23 "] {
+            assert_eq!(Rule::parse(rulestring), Ok(life()), "{}", rulestring);
+        }
+        assert_eq!("B3/S23".parse::<Rule>(), Ok(life()));
+    }
+
+    #[test]
+    fn round_trips () {
+        for rulestring in [
+            "B3/S23",
+            "B36/S23",
+            "B/S012345678",
+            "B0/S",
+            "B2/S34H",
+            "B2/S013V",
+            "R5,C0,M1,S34..58,B34..45,NM",
+            "R2,C0,M0,S1..3,B2..2,NN",
+            "R7,C0,M1,S1..20,B5..9,NC",
+        ] {
+            let rule = Rule::parse(rulestring).unwrap();
+            assert_eq!(rule.to_string(), rulestring);
+            assert_eq!(Rule::parse(&rule.to_string()), Ok(rule));
+        }
+        assert_eq!(Rule::parse("23/3").unwrap().to_string(), "B3/S23");
+        assert_eq!(Rule::parse("R5,C0,M1,S34..58,B34..45").unwrap().to_string(), "R5,C0,M1,S34..58,B34..45,NM");
+    }
+
+    #[test]
+    fn errors () {
+        assert_eq!(Rule::parse(" "), Err(RuleError::Empty));
+        assert_eq!(Rule::parse("23"), Err(RuleError::MissingSlash));
+        assert_eq!(Rule::parse("2%/3"), Err(RuleError::UnexpectedChar { c: '%', pos: 1 }));
+        assert_eq!(Rule::parse("B3/S29"), Err(RuleError::InvalidCount { c: '9', pos: 5 }));
+        assert_eq!(Rule::parse("B3/S2%"), Err(RuleError::UnexpectedChar { c: '%', pos: 5 }));
+        assert_eq!(Rule::parse("B3/S23/B4"), Err(RuleError::DuplicatePart { part: 'B', pos: 7 }));
+        assert_eq!(Rule::parse("B3"), Err(RuleError::MissingPart { part: 'S' }));
+        assert_eq!(Rule::parse("3/S23x"), Err(RuleError::CountsWithoutPart { pos: 0 }));
+        assert_eq!(Rule::parse("B3/S2x"), Err(RuleError::InvalidLetter { c: 'x', count: 2, pos: 5 }));
+        assert_eq!(Rule::parse("B3-/S23"), Err(RuleError::InvalidLetter { c: '-', count: 3, pos: 2 }));
+        assert_eq!(Rule::parse("B3/S23-"), Err(RuleError::InvalidLetter { c: '-', count: 3, pos: 6 }));
+        assert_eq!(Rule::parse("B2/S37H"), Err(RuleError::CountTooHigh { count: 7, max: 6 }));
+        assert_eq!(Rule::parse("B2/S5V"), Err(RuleError::CountTooHigh { count: 5, max: 4 }));
+        assert_eq!(Rule::parse("R0,C0,M1,S1..2,B1..2"), Err(RuleError::InvalidRadius { pos: 1 }));
+        assert_eq!(Rule::parse("R2,C3,M0,S1..2,B1..2"), Err(RuleError::UnsupportedStates { pos: 4 }));
+        assert_eq!(Rule::parse("R2,C0,M0,S1..2,B1-2"), Err(RuleError::InvalidRange { part: 'B', pos: 16 }));
+        assert_eq!(Rule::parse("R2,C0,M0,S1..2,B1..2,R3"), Err(RuleError::DuplicatePart { part: 'R', pos: 21 }));
+        assert_eq!(Rule::parse("R2,C0,S1..2,B1..2"), Err(RuleError::MissingPart { part: 'M' }));
+    }
+
+    // the MAP rule with the same transitions
+    fn to_map (rule: &Rule) -> Rule {
+        let mut bits = [0u8; 64];
+        for env in 0..512 {
+            if rule.next(env) {
+                let i = map_i(env);
+                bits[i/8] |= 1 << (7 - i%8);
+            }
+        }
+        Rule { transition: Transition::Map(bits), states: rule.states }
+    }
+
+    #[test]
+    fn hensel () {
+        for rulestring in ["B2n3/S23-q", "B2-a/S12", "B3/S2ck3", "B2ei3-jr/S1c2-kn4w"] {
+            let rule = Rule::parse(rulestring).unwrap();
+            assert_eq!(rule.to_string(), rulestring);
+        }
+        // all letters make it totalistic
+        assert_eq!(Rule::parse("B3cekaiynjqr/S2ceaikn3"), Ok(life()));
+        let rule = Rule::parse("B2n/S").unwrap();
+        // NE and SW, and rotated: NW and SE
+        assert!(rule.next(0x044));
+        assert!(rule.next(0x101));
+        // N and NE (2a), N and S (2i)
+        assert!(!rule.next(0x003));
+        assert!(!rule.next(0x082));
+    }
+
+    #[test]
+    fn map () {
+        let life_map = "MAPARYXfhZofugWaH7oaIDogBZofuhogOiAaIDogIAAgAAWaH7oaIDogGiA6ICAAIAAaIDogIAAgACAAIAAAAAAAA";
+        let rule = Rule::parse(life_map).unwrap();
+        assert_eq!(rule.to_string(), life_map);
+        assert_eq!(to_map(&life()), rule);
+        assert_eq!(Rule::parse(&format!("{}==", life_map)), Ok(rule));
+        assert_eq!(Rule::parse("MAPARYX"), Err(RuleError::InvalidMap));
+        assert_eq!(Rule::parse(&life_map.replace('R', "*")), Err(RuleError::InvalidMap));
+    }
+
+    #[test]
+    fn generations () {
+        let star_wars = Rule::parse("B2/S345/C4").unwrap();
+        assert_eq!(star_wars.states, 4);
+        assert_eq!(Rule::parse("345/2/4"), Ok(star_wars));
+        assert_eq!(star_wars.to_string(), "B2/S345/C4");
+        let brians_brain = Rule::parse("/2/3").unwrap();
+        assert_eq!(brians_brain.to_string(), "B2/S/C3");
+        assert_eq!(Rule::parse("B2/S34H/C3").unwrap().to_string(), "B2/S34H/C3");
+        assert_eq!(Rule::parse("B2/S/C1"), Err(RuleError::InvalidStates { pos: 6 }));
+        assert_eq!(Rule::parse("23/3/257"), Err(RuleError::InvalidStates { pos: 5 }));
+        // born with 2 neighbours, then dying: 1 -> 2 -> 0
+        let (n, w) = (0x001 | 0x004, 0x001 | 0x004 | 0x100);
+        assert_eq!(brians_brain.next_state(0, n), 1);
+        assert_eq!(brians_brain.next_state(0, w), 0);
+        assert_eq!(brians_brain.next_state(1, n), 2);
+        assert_eq!(brians_brain.next_state(2, n), 0);
+        // survives with 3 neighbours, and dying cells don't come back
+        assert_eq!(star_wars.next_state(1, w), 1);
+        assert_eq!(star_wars.next_state(1, n), 2);
+        assert_eq!(star_wars.next_state(2, n), 3);
+        assert_eq!(star_wars.next_state(3, w), 0);
+    }
+
+    // base64 has '/', so a MAP payload may contain "/C" like the states suffix
+    #[test]
+    fn map_with_slash () {
+        let life_map = to_map(&life()).to_string();
+        for at in [10, 40, 86] {
+            let rulestring = format!("{}/C{}", &life_map[..at], &life_map[at+2..]);
+            let rule = Rule::parse(&rulestring).unwrap();
+            assert_eq!(rule.to_string(), rulestring);
+            let generations = Rule::parse(&format!("{}/C3", rulestring)).unwrap();
+            assert_eq!(generations, Rule { transition: rule.transition, states: 3 });
+            assert_eq!(Rule::parse(&generations.to_string()), Ok(generations));
+        }
+        assert_eq!(Rule::parse(&format!("{}/C", life_map)), Err(RuleError::InvalidStates { pos: 91 }));
+        assert_eq!(Rule::parse(&format!("{}x", life_map)), Err(RuleError::UnexpectedChar { c: 'x', pos: 89 }));
+    }
+
+    // Hensel and MAP rules end up in the same lookup table as outer totalistic ones.
+    #[test]
+    fn tables () {
+        let all = [1, 3, 63, 1023, 8191, 1023, 63, 3, 1];
+        let mut borns = [0; 9];
+        let mut survives = [0; 9];
+        borns[3] = all[3];
+        survives[2] = all[2];
+        survives[3] = all[3];
+        let isotropic = Rule { transition: Transition::Isotropic { borns, survives }, states: 2 };
+        let table = Table::from_rule(&life());
+        assert!(table.values == Table::from_rule(&isotropic).values);
+        assert!(table.values == Table::from_rule(&to_map(&life())).values);
+        for rulestring in ["B2n3/S23-q", "B2ei3-jr/S1c2-kn4w", "B2/S34H", "B2/S013V"] {
+            let rule = Rule::parse(rulestring).unwrap();
+            assert!(Table::from_rule(&rule).values == Table::from_rule(&to_map(&rule)).values, "{}", rulestring);
+        }
+    }
+}
@@ -1,21 +1,74 @@
-use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use sdl2::mouse::MouseWheelDirection;
 use sdl2::Sdl;
-use sdl2::surface::Surface;
-use sdl2::rect::Point;
-use sdl2::rect::Rect;
 use sdl2::render::Canvas;
-use sdl2::image::Sdl2ImageContext;
-use sdl2::image::SaveSurface;
+use sdl2::render::Texture;
 
-use automata;
+use simple_cells::automata;
+use simple_cells::backend::Blocks;
+use simple_cells::png;
+use simple_cells::rule::Neighbourhood;
 
 
+/*
+The part of the field that is shown: "zoom" is the magnification of the view
+of the whole field (1: all of it), and (x, y) is the top left corner of the
+window, as fractions of the width and height of the field. For hexagonal
+rules, that's the sheared field (see "present").
+*/
+
+#[derive(Clone, Copy)]
+struct Viewport {
+    zoom: f64,
+    x: f64,
+    y: f64,
+}
+
+/*
+What the left mouse button does to the cells under the brush: make them live,
+dead, or the opposite of what they are. Toggling changes each cell only once
+while the button is held, however often the brush passes over it.
+*/
+
+#[derive(Clone, Copy)]
+enum Tool {
+    Draw,
+    Erase,
+    Toggle,
+}
+
+// the brush is a square of up to that many cells
+const MAX_BRUSH_SIZE: usize = 64;
+// the zoom factor for a step of the mouse wheel, or a key press
+const ZOOM_STEP: f64 = 1.25;
+// at most that many pixels per cell
+const MAX_CELL_SIZE: f64 = 32.0;
+// "." doubles the rounds per frame up to that many
+const MAX_RPF: usize = 1 << 20;
+
 pub struct Window {
     sdl_context: Sdl,
-    #[allow(dead_code)] sdl_img_context: Sdl2ImageContext,
     sdl_canvas: Canvas<sdl2::video::Window>,
+    // streamed to every frame (see "present")
+    texture: Option<Texture>,
+    view: Viewport,
+    // "density": a grey level per window pixel when zoomed out, instead of one cell
+    density: bool,
+    density_buf: Vec<u8>,
+    tool: Tool,
+    brush_size: usize,
+    // the cells toggled since the left mouse button was pressed
+    toggled: std::collections::HashSet<(usize, usize)>,
+    // see "rounds"
+    pub paused: bool,
+    steps: usize,
+    step_n: usize,
+    rpf: usize,
+    rpf_target: Option<usize>,
+    title: String,
     pub exit_issued: bool,
 }
 
@@ -24,7 +77,6 @@ impl Window
     pub fn new (prefs_json: &json::JsonValue) -> Window
     {
         let sdl_context = sdl2::init().unwrap();
-        let sdl_img_context = sdl2::image::init(sdl2::image::InitFlag::PNG).unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let w: u32 = prefs_json["window_w"].as_u32().unwrap();
         let h: u32 = prefs_json["window_h"].as_u32().unwrap();
@@ -37,97 +89,302 @@ impl Window
         canvas.present();
         Window {
             sdl_context: sdl_context,
-            sdl_img_context: sdl_img_context,
             sdl_canvas: canvas,
+            texture: None,
+            density: prefs_json["density"].as_bool().unwrap_or(false),
+            density_buf: Vec::new(),
+            view: Viewport { zoom: 1.0, x: 0.0, y: 0.0 },
+            tool: Tool::Draw,
+            brush_size: 1,
+            toggled: std::collections::HashSet::new(),
+            paused: false,
+            steps: 0,
+            step_n: prefs_json["step_n"].as_usize().unwrap_or(100),
+            rpf: 1,
+            rpf_target: None,
+            title: String::new(),
             exit_issued: false,
         }
     }
 
-    pub fn present (&mut self, automata: &automata::Automata)
+    /*
+    The rounds to play in the next frame: while running, rpf (as many as
+    "fps" allows) or the rounds per frame chosen with "." and ","; while
+    paused, the rounds asked for with "s" (1) and "n" (step_n) since.
+    */
+    pub fn rounds (&mut self, rpf: usize) -> usize
+    {
+        self.rpf = rpf;
+        if self.paused {
+            std::mem::take(&mut self.steps)
+        } else {
+            self.rpf_target.unwrap_or(rpf)
+        }
+    }
+
+    // n, and whether the run is going on, in the title
+    pub fn show_status (&mut self, n: usize, period: Option<usize>)
+    {
+        let mut title = format!("simple_cells: n={}", n);
+        if self.paused {
+            title.push_str(", paused");
+        } else {
+            match self.rpf_target {
+                Some(rpf) => title.push_str(&format!(", {} rounds/frame", rpf)),
+                None => title.push_str(&format!(", {} rounds/frame (auto)", self.rpf)),
+            }
+        }
+        match period {
+            Some(1) => title.push_str(", static"),
+            Some(p) => title.push_str(&format!(", period {}", p)),
+            None => {},
+        }
+        if title != self.title {
+            self.sdl_canvas.window_mut().set_title(&title).unwrap();
+            self.title = title;
+        }
+    }
+
+    pub fn present (&mut self, automata: &mut automata::Automata)
     {
         let (win_w, win_h) = self.sdl_canvas.output_size().unwrap();
         let (w, h) = (automata.w, automata.h);
-        let win_w_by_x = (w as f64) / (win_w as f64);
-        let win_h_by_y = (h as f64) / (win_h as f64);
-        self.sdl_canvas.set_draw_color(Color::RGB(0,0,0));
-        self.sdl_canvas.fill_rect(
-            Rect::new(0, 0, win_w, win_h)
-        ).unwrap();
-        self.sdl_canvas.set_draw_color(Color::RGB(255,255,255));
-        for wy in 0..win_h {
-            for wx in 0..win_w {
-                let x = (wx as f64 * win_w_by_x).round() as usize;
-                let y = (wy as f64 * win_h_by_y).round() as usize;
-                if x >= automata.w {
-                    continue;
-                }
-                if y >= automata.h {
+        // Hexagonal rules are sheared: each row half a cell left of the one above,
+        // so that the 6 neighbours (see rule.rs) surround a cell evenly.
+        let hexagonal = automata.rule.neighbourhood() == Neighbourhood::Hexagonal;
+        let shear_w = if hexagonal { (h as f64) / 2.0 } else { 0.0 };
+        // the whole (sheared) field, and the cells per window pixel
+        let (full_w, full_h) = (w as f64 + shear_w, h as f64);
+        let view = self.view;
+        let (view_x, view_y) = (view.x * full_w, view.y * full_h);
+        let x_by_wx = full_w / (win_w as f64 * view.zoom);
+        let y_by_wy = full_h / (win_h as f64 * view.zoom);
+        // the texture matches the window (or the canvas, on high DPI screens)
+        let texture_size = self.texture.as_ref().map(|texture| {
+            let query = texture.query();
+            (query.width, query.height)
+        });
+        if texture_size != Some((win_w, win_h)) {
+            if let Some(texture) = self.texture.take() {
+                // not in use anymore, and made by sdl_canvas, which is still there
+                unsafe { texture.destroy(); }
+            }
+            self.texture = Some(self.sdl_canvas.create_texture_streaming(
+                PixelFormatEnum::RGB888, win_w, win_h
+            ).unwrap());
+        }
+        // for every window column, twice the x of the cell at the centre of
+        // its pixels, in the sheared field; a row y adds y for the shear
+        let columns: Vec<i64> = (0..win_w)
+            .map(|wx| (2.0 * (view_x + (wx as f64 + 0.5) * x_by_wx - shear_w)).floor() as i64)
+            .collect();
+        // the colors of the states, as RGB888 pixels
+        let states = automata.rule.states;
+        let palette: Vec<u32> = (0..states.max(2)).map(|state| match state {
+            0 => 0,
+            1 => 0xffffff,
+            state => {
+                let (r, g, b) = png::dying_color(state as u8, states);
+                (r as u32) << 16 | (g as u32) << 8 | b as u32
+            },
+        }).collect();
+        // zoomed out: how many of the cells of each pixel are alive (on the backend)
+        let density = self.density && (x_by_wx > 1.0 || y_by_wy > 1.0);
+        if density {
+            let blocks = Blocks {
+                x0: view_x - shear_w,
+                y0: view_y,
+                block_w: x_by_wx,
+                block_h: y_by_wy,
+                shear: if hexagonal { 0.5 } else { 0.0 },
+                nx: win_w as usize,
+                ny: win_h as usize,
+            };
+            self.density_buf.resize(blocks.nx * blocks.ny, 0);
+            automata.density(&blocks, &mut self.density_buf);
+        }
+        let density_buf = &self.density_buf;
+        // one pass over the window, straight from the packed field (downloaded
+        // from the backend only for this)
+        let field = if density { None } else { Some(automata.field()) };
+        let texture = self.texture.as_mut().unwrap();
+        texture.with_lock(None, |pixels, pitch| {
+            let field = match field {
+                Some(field) => field,
+                None => {
+                    for (wy, grey_row) in density_buf.chunks_exact(win_w as usize).enumerate() {
+                        let row = &mut pixels[wy*pitch .. wy*pitch + 4*win_w as usize];
+                        for (pixel, &grey) in row.chunks_exact_mut(4).zip(grey_row) {
+                            let grey = grey as u32;
+                            pixel.copy_from_slice(&(grey << 16 | grey << 8 | grey).to_ne_bytes());
+                        }
+                    }
+                    return;
+                },
+            };
+            let w8 = field.w8;
+            for wy in 0..win_h as usize {
+                let row = &mut pixels[wy*pitch .. wy*pitch + 4*win_w as usize];
+                let y = (view_y + (wy as f64 + 0.5) * y_by_wy).floor() as usize;
+                if y >= h {
+                    row.fill(0);
                     continue;
                 }
-                if automata.get(x, y) {
-                    self.sdl_canvas.draw_point(
-                        Point::new(wx as i32, wy as i32)
-                    ).unwrap();
+                let shift = if hexagonal { y as i64 } else { 0 };
+                let data = &field.data()[y*w8 .. (y+1)*w8];
+                let row_states = if field.has_states() { &field.states()[y*w .. (y+1)*w] } else { &[] };
+                for (pixel, &column) in row.chunks_exact_mut(4).zip(&columns) {
+                    let x = (column + shift).div_euclid(2);
+                    let color = if x < 0 || x >= w as i64 {
+                        0
+                    } else if row_states.is_empty() {
+                        let x = x as usize;
+                        palette[((data[x/8] >> (x%8)) & 1) as usize]
+                    } else {
+                        palette[row_states[x as usize] as usize]
+                    };
+                    pixel.copy_from_slice(&color.to_ne_bytes());
                 }
             }
-        }
+        }).unwrap();
+        self.sdl_canvas.copy(texture, None, None).unwrap();
         self.sdl_canvas.present();
-        // Exit issued? This is a variant of the SDL2 Rust binding example: https://docs.rs/sdl2/0.34.5/sdl2/index.html
+        // Exit issued, zoom and pan, or editing? This is a variant of the SDL2 Rust binding example: https://docs.rs/sdl2/0.34.5/sdl2/index.html
         let mut event_pump = self.sdl_context.event_pump().unwrap();
+        let mouse = event_pump.mouse_state();
+        let (win_w, win_h) = (win_w as f64, win_h as f64);
+        let max_zoom = (MAX_CELL_SIZE * (full_w / win_w).max(full_h / win_h)).max(1.0);
+        // the cell under a point of the window, as for drawing (see "columns")
+        let cell_at = |wx: f64, wy: f64| {
+            let y = (view_y + (wy + 0.5) * y_by_wy).floor();
+            let x = view_x + (wx + 0.5) * x_by_wx - shear_w + if hexagonal { y / 2.0 } else { 0.0 };
+            (x.floor() as i64, y as i64)
+        };
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     self.exit_issued = true;
                 },
+                // zoom toward the cursor
+                Event::MouseWheel { y, direction, .. } => {
+                    let steps = if direction == MouseWheelDirection::Flipped { -y } else { y };
+                    self.zoom_at(
+                        ZOOM_STEP.powi(steps), max_zoom,
+                        mouse.x() as f64 / win_w, mouse.y() as f64 / win_h
+                    );
+                },
+                // edit with the left button
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => {
+                    self.toggled.clear();
+                    self.paint(automata, cell_at(x as f64, y as f64));
+                },
+                Event::MouseMotion { mousestate, x, y, xrel, yrel, .. } if mousestate.left() => {
+                    // along the way from the last point, so that fast strokes leave no gaps
+                    let steps = xrel.abs().max(yrel.abs()).max(1);
+                    for step in 1..=steps {
+                        let f = step as f64 / steps as f64;
+                        self.paint(automata, cell_at(
+                            x as f64 - xrel as f64 * (1.0 - f),
+                            y as f64 - yrel as f64 * (1.0 - f)
+                        ));
+                    }
+                },
+                // drag to pan
+                Event::MouseMotion { mousestate, xrel, yrel, .. }
+                        if mousestate.middle() || mousestate.right() => {
+                    self.view.x -= xrel as f64 / (win_w * self.view.zoom);
+                    self.view.y -= yrel as f64 / (win_h * self.view.zoom);
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } => match keycode {
+                    // an eighth of the window
+                    Keycode::Left => self.view.x -= 1.0 / (8.0 * self.view.zoom),
+                    Keycode::Right => self.view.x += 1.0 / (8.0 * self.view.zoom),
+                    Keycode::Up => self.view.y -= 1.0 / (8.0 * self.view.zoom),
+                    Keycode::Down => self.view.y += 1.0 / (8.0 * self.view.zoom),
+                    Keycode::Plus | Keycode::Equals | Keycode::KpPlus =>
+                        self.zoom_at(ZOOM_STEP, max_zoom, 0.5, 0.5),
+                    Keycode::Minus | Keycode::KpMinus =>
+                        self.zoom_at(1.0 / ZOOM_STEP, max_zoom, 0.5, 0.5),
+                    // back to the whole field
+                    Keycode::Home | Keycode::Num0 | Keycode::Kp0 =>
+                        self.view = Viewport { zoom: 1.0, x: 0.0, y: 0.0 },
+                    Keycode::D => self.tool = Tool::Draw,
+                    Keycode::E => self.tool = Tool::Erase,
+                    Keycode::T => self.tool = Tool::Toggle,
+                    Keycode::LeftBracket => self.brush_size = (self.brush_size - 1).max(1),
+                    Keycode::RightBracket => self.brush_size = (self.brush_size + 1).min(MAX_BRUSH_SIZE),
+                    Keycode::Space => {
+                        self.paused = !self.paused;
+                        // steps queued but not played yet don't wait for the next pause
+                        if !self.paused {
+                            self.steps = 0;
+                        }
+                    },
+                    // stepping pauses
+                    Keycode::S => {
+                        self.paused = true;
+                        self.steps += 1;
+                    },
+                    Keycode::N => {
+                        self.paused = true;
+                        self.steps += self.step_n;
+                    },
+                    // faster, slower, or back to as fast as "fps" allows
+                    Keycode::Period => self.rpf_target = Some(self.rpf_target.unwrap_or(self.rpf).min(MAX_RPF / 2) * 2),
+                    Keycode::Comma => self.rpf_target = Some((self.rpf_target.unwrap_or(self.rpf) / 2).max(1)),
+                    Keycode::Slash => self.rpf_target = None,
+                    _ => {}
+                },
                 _ => {}
             }
         }
+        // keep the field in the window
+        self.view.x = self.view.x.clamp(0.0, 1.0 - 1.0 / self.view.zoom);
+        self.view.y = self.view.y.clamp(0.0, 1.0 - 1.0 / self.view.zoom);
     }
 
-    pub fn seed_png (&self) -> ((usize,usize), Vec<Vec<u8>>)
+    /*
+    Applies the tool to the cells under the brush, centred on the cell at
+    (cx, cy), which may be outside the field. Automata::set leaves the cells
+    of the backend for the next "play" to upload.
+    */
+    fn paint (&mut self, automata: &mut automata::Automata, (cx, cy): (i64, i64))
     {
-        let surf: Surface = sdl2::image::LoadSurface::from_file("seed.png").unwrap();
-        let w = surf.width() as usize;
-        let h = surf.height() as usize;
-        let mut rows = Vec::<Vec<u8>>::with_capacity(h);
-        unsafe {
-            let pitch = (*surf.raw()).pitch as usize;
-            let pixels = (*surf.raw()).pixels as *const u8;
-            for y in 0..h {
-                let mut row = vec![0; ((w as f64) / 8f64).ceil() as usize];
-                for x in 0..w {
-                    let v = *pixels.offset((y*pitch + x) as isize) == 0;
-                    row[x/8] |= (v as u8) << (x%8);
+        let size = self.brush_size as i64;
+        for y in cy - (size-1)/2 ..= cy + size/2 {
+            for x in cx - (size-1)/2 ..= cx + size/2 {
+                if x < 0 || y < 0 || x >= automata.w as i64 || y >= automata.h as i64 {
+                    continue;
                 }
-                rows.push(row);
+                let (x, y) = (x as usize, y as usize);
+                let v = match self.tool {
+                    Tool::Draw => true,
+                    Tool::Erase => false,
+                    Tool::Toggle => {
+                        if !self.toggled.insert((x, y)) {
+                            continue;
+                        }
+                        automata.state(x, y) != 1
+                    },
+                };
+                automata.set(x, y, v);
             }
         }
-        ((w,h), rows)
     }
 
-    pub fn snapshot_png (&self, automata: &automata::Automata, path: &str)
+    /*
+    Zooms by factor (up to max_zoom), keeping the cell under the point at
+    (fx, fy) in place; fx and fy are fractions of the window width and height.
+    */
+    fn zoom_at (&mut self, factor: f64, max_zoom: f64, fx: f64, fy: f64)
     {
-        let (w, h) = (automata.w, automata.h);
-        let surf = Surface::new(
-            w as u32,
-            h as u32,
-            sdl2::pixels::PixelFormatEnum::RGB332 //1 byte per pixel
-        ).unwrap();
-        let pitch = surf.pitch();
-        unsafe {
-            let pixels = (*surf.raw()).pixels as *mut u8;
-            for y in 0..h {
-                for x in 0..w {
-                    let pixel_i = (y*(pitch as usize) + x) as isize;
-                    if automata.get(x,y) {
-                        *pixels.offset(pixel_i) = 0;
-                    } else {
-                        *pixels.offset(pixel_i) = 255;
-                    }
-                }
-            }
-        }
-        surf.save(path).unwrap();
+        let view = &mut self.view;
+        let zoom = (view.zoom * factor).clamp(1.0, max_zoom);
+        // the visible fraction of the field before and after
+        let (before, after) = (1.0 / view.zoom, 1.0 / zoom);
+        view.zoom = zoom;
+        view.x += fx * (before - after);
+        view.y += fy * (before - after);
     }
-}
\ No newline at end of file
+}